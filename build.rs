@@ -414,6 +414,7 @@ fn main() {
             "flow-from",
             "flow-into",
             "font",
+            "font-display",
             "font-family",
             "font-feature-settings",
             "font-kerning",
@@ -575,6 +576,7 @@ fn main() {
             "speak-numeral",
             "speak-punctuation",
             "speech-rate",
+            "src",
             "stop-color",
             "stop-opacity",
             "stress",
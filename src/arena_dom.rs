@@ -22,21 +22,88 @@ use std::ptr;
 
 use html5ever::interface::tree_builder::{ElementFlags, NodeOrText, QuirksMode, TreeSink};
 use html5ever::serialize::TraversalScope::{ChildrenOnly, IncludeNode};
-use html5ever::serialize::{Serialize, Serializer, TraversalScope};
+use html5ever::serialize::{serialize, Serialize, SerializeOpts, Serializer, TraversalScope};
 use html5ever::tendril::StrTendril;
-use html5ever::{Attribute as HTML5everAttribute, ExpandedName, LocalName, QualName};
+use html5ever::tendril::TendrilSink;
+use html5ever::{
+    parse_fragment, Attribute as HTML5everAttribute, ExpandedName, LocalName, QualName,
+};
 
-use crate::css_parser::{serialize_css_declarations, serialize_css_rules, CssDeclaration, CssRule};
+use crate::css_parser::{
+    serialize_css_rules, serialize_css_style_attribute, serialize_css_stylesheet, CssDeclaration,
+    CssRule, CssSerialization,
+};
+use crate::css_selector::{matches_css_selector, parse_css_selector_list, CssSelector};
 
 pub fn create_element<'arena>(arena: Arena<'arena>, name: &str) -> Ref<'arena> {
     arena.alloc(Node::new(NodeData::Element {
-        name: QualName::new(None, ns!(), LocalName::from(name)),
+        name: QualName::new(None, ns!(html), LocalName::from(name)),
         attrs: RefCell::new(vec![]),
         template_contents: None,
         mathml_annotation_xml_integration_point: false,
     }))
 }
 
+pub fn create_text<'arena>(arena: Arena<'arena>, text: &str) -> Ref<'arena> {
+    arena.alloc(Node::new(NodeData::Text {
+        contents: RefCell::new(StrTendril::from(text)),
+    }))
+}
+
+// A typo in a selector would otherwise just match nothing.
+fn parse_selectors(selectors: &str) -> Vec<CssSelector> {
+    parse_css_selector_list(selectors)
+        .unwrap_or_else(|| panic!("invalid CSS selector list: {:?}", selectors))
+}
+
+/// Parses `html` as the contents of a `<body>` and returns the top-level nodes, unattached. If
+/// `trusted`, the sanitizer leaves the nodes alone. Otherwise they are sanitized like the rest of
+/// the document once the traversal reaches them, so they need to be inserted under or after the
+/// node being transformed. Note that `round_trip_check` re-sanitizes the whole serialized output,
+/// trusted nodes included.
+pub fn parse_html<'arena>(arena: Arena<'arena>, html: &str, trusted: bool) -> Vec<Ref<'arena>> {
+    let sink = Sink {
+        arena,
+        document: arena.alloc(Node::new(NodeData::Document)),
+        quirks_mode: QuirksMode::NoQuirks,
+    };
+    let document = parse_fragment(
+        sink,
+        Default::default(),
+        QualName::new(None, ns!(html), local_name!("body")),
+        vec![],
+    )
+    .one(html);
+
+    // Fragments are parsed into an `<html>` element under the document.
+    let nodes: Vec<Ref<'arena>> = match document.first_child.get() {
+        Some(root) => root.children().collect(),
+        None => vec![],
+    };
+    for node in nodes.iter() {
+        node.detach();
+        node.trusted.set(trusted);
+    }
+    nodes
+}
+
+// Overwrites the value of the attribute if the element already has it so that no duplicate
+// attributes get serialized.
+pub fn set_text_attribute(attrs: &mut Vec<Attribute>, name: &LocalName, value: &str) {
+    let new_attr = Attribute::Text(HTML5everAttribute {
+        name: QualName::new(None, ns!(), name.clone()),
+        value: StrTendril::from(value),
+    });
+    for attr in attrs.iter_mut() {
+        if attr.name().local == *name {
+            *attr = new_attr;
+            return;
+        }
+    }
+
+    attrs.push(new_attr);
+}
+
 pub type Arena<'arena> = &'arena typed_arena::Arena<Node<'arena>>;
 
 pub type Ref<'arena> = &'arena Node<'arena>;
@@ -57,12 +124,17 @@ pub struct Node<'arena> {
     pub first_child: Link<'arena>,
     pub last_child: Link<'arena>,
     pub data: NodeData<'arena>,
+    // Trusted nodes, and everything under them, are skipped by the sanitizer.
+    pub trusted: Cell<bool>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct StyleAttribute {
     pub name: QualName,
     pub value: Vec<CssDeclaration>,
+    // The attribute value the declarations were parsed from, for `CssSerialization::Preserved`.
+    pub source: String,
+    pub serialization: CssSerialization,
     // Need to store the serialized value to the arena because html5ever expects a &str for
     // attribute values during serlialization. If this is None, `serialize` will construct a String
     // from serializing the `CssDeclaration`s, store it here, and then reference it with
@@ -70,12 +142,32 @@ pub struct StyleAttribute {
     pub serialized_value: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Attribute {
     Style(StyleAttribute),
     Text(HTML5everAttribute),
 }
 
+impl Attribute {
+    pub fn name(&self) -> &QualName {
+        match self {
+            Attribute::Style(attr) => &attr.name,
+            Attribute::Text(attr) => &attr.name,
+        }
+    }
+
+    pub fn value(&self) -> Cow<'_, str> {
+        match self {
+            Attribute::Style(attr) => Cow::Owned(serialize_css_style_attribute(
+                &attr.value,
+                &attr.source,
+                attr.serialization,
+            )),
+            Attribute::Text(attr) => Cow::Borrowed(&attr.value),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum NodeData<'arena> {
     Document,
@@ -89,6 +181,8 @@ pub enum NodeData<'arena> {
     },
     StyleSheet {
         rules: Vec<CssRule>,
+        source: String,
+        serialization: CssSerialization,
     },
     Comment {
         contents: StrTendril,
@@ -114,6 +208,7 @@ impl<'arena> Node<'arena> {
             first_child: Cell::new(None),
             last_child: Cell::new(None),
             data,
+            trusted: Cell::new(false),
         }
     }
 
@@ -187,6 +282,181 @@ impl<'arena> Node<'arena> {
         }
     }
 
+    /// Puts `new_node` where this node is in the tree and detaches this node.
+    pub fn replace_with(&'arena self, new_node: &'arena Self) {
+        self.insert_before(new_node);
+        self.detach();
+    }
+
+    /// Puts `wrapper` where this node is in the tree and moves this node into it as its last
+    /// child.
+    pub fn wrap_in(&'arena self, wrapper: &'arena Self) {
+        self.insert_before(wrapper);
+        wrapper.append(self);
+    }
+
+    /// Copies this node and everything under it into `arena`. The copy isn't attached to the tree.
+    pub fn clone_subtree(&'arena self, arena: Arena<'arena>) -> Ref<'arena> {
+        let data = match self.data {
+            NodeData::Document => NodeData::Document,
+            NodeData::Doctype {
+                ref name,
+                ref public_id,
+                ref system_id,
+            } => NodeData::Doctype {
+                name: name.clone(),
+                public_id: public_id.clone(),
+                system_id: system_id.clone(),
+            },
+            NodeData::Text { ref contents } => NodeData::Text {
+                contents: contents.clone(),
+            },
+            NodeData::StyleSheet {
+                ref rules,
+                ref source,
+                serialization,
+            } => NodeData::StyleSheet {
+                rules: rules.clone(),
+                source: source.clone(),
+                serialization,
+            },
+            NodeData::Comment { ref contents } => NodeData::Comment {
+                contents: contents.clone(),
+            },
+            NodeData::Element {
+                ref name,
+                ref attrs,
+                template_contents,
+                mathml_annotation_xml_integration_point,
+            } => NodeData::Element {
+                name: name.clone(),
+                attrs: attrs.clone(),
+                template_contents: template_contents.map(|contents| contents.clone_subtree(arena)),
+                mathml_annotation_xml_integration_point,
+            },
+            NodeData::ProcessingInstruction {
+                ref target,
+                ref contents,
+            } => NodeData::ProcessingInstruction {
+                target: target.clone(),
+                contents: contents.clone(),
+            },
+        };
+        let copy = arena.alloc(Node::new(data));
+        copy.trusted.set(self.trusted.get());
+        for child in self.children() {
+            copy.append(child.clone_subtree(arena));
+        }
+        copy
+    }
+
+    /// Iterates over the children of this node.
+    pub fn children(&'arena self) -> Siblings<'arena> {
+        Siblings {
+            next: self.first_child.get(),
+        }
+    }
+
+    /// Iterates over the parent of this node, its parent, and so on up to the document.
+    pub fn ancestors(&'arena self) -> Ancestors<'arena> {
+        Ancestors {
+            next: self.parent.get(),
+        }
+    }
+
+    /// The value of the attribute named `name`, if this is an element that has one.
+    pub fn get_attr(&self, name: &str) -> Option<String> {
+        if let NodeData::Element { ref attrs, .. } = self.data {
+            attrs
+                .borrow()
+                .iter()
+                .find(|attr| &*attr.name().local == name)
+                .map(|attr| attr.value().into_owned())
+        } else {
+            None
+        }
+    }
+
+    pub fn set_attr(&self, name: &str, value: &str) {
+        if let NodeData::Element { ref attrs, .. } = self.data {
+            set_text_attribute(&mut attrs.borrow_mut(), &LocalName::from(name), value);
+        }
+    }
+
+    pub fn remove_attr(&self, name: &str) {
+        if let NodeData::Element { ref attrs, .. } = self.data {
+            attrs
+                .borrow_mut()
+                .retain(|attr| &*attr.name().local != name);
+        }
+    }
+
+    /// The text of this node and everything under it, like the DOM's `textContent`.
+    pub fn text_content(&'arena self) -> String {
+        let mut text = String::new();
+        for node in std::iter::once(self).chain(self.descendants()) {
+            match node.data {
+                NodeData::Text { ref contents } => text.push_str(&contents.borrow()),
+                NodeData::StyleSheet {
+                    ref rules,
+                    ref source,
+                    serialization,
+                } => text.push_str(&serialize_css_stylesheet(rules, source, serialization)),
+                _ => {}
+            }
+        }
+        text
+    }
+
+    /// Replaces the children of this node with a single text node containing `text`, or the
+    /// contents of this node if it's a text node itself.
+    pub fn set_text(&'arena self, arena: Arena<'arena>, text: &str) {
+        if let NodeData::Text { ref contents } = self.data {
+            contents.replace(StrTendril::from(text));
+            return;
+        }
+        while let Some(child) = self.first_child.get() {
+            child.detach();
+        }
+        if !text.is_empty() {
+            self.append(create_text(arena, text));
+        }
+    }
+
+    /// Serializes the children of this node to HTML.
+    pub fn inner_html(&self) -> String {
+        let parent = match self.data {
+            NodeData::Element { ref name, .. } => Some(name.clone()),
+            _ => None,
+        };
+        self.to_html(ChildrenOnly(parent))
+    }
+
+    /// Serializes this node and its children to HTML.
+    pub fn outer_html(&self) -> String {
+        match self.data {
+            NodeData::Document => self.to_html(ChildrenOnly(None)),
+            _ => self.to_html(IncludeNode),
+        }
+    }
+
+    fn to_html(&self, traversal_scope: TraversalScope) -> String {
+        let mut html = Vec::new();
+        let opts = SerializeOpts {
+            traversal_scope,
+            ..Default::default()
+        };
+        serialize(&mut html, self, opts).expect("writing to a Vec can't fail");
+        String::from_utf8(html).expect("serialized HTML is always UTF-8")
+    }
+
+    pub fn prepend(&'arena self, new_child: &'arena Self) {
+        match self.first_child.get() {
+            Some(first_child) => first_child.insert_before(new_child),
+            None => self.append(new_child),
+        }
+    }
+
     pub fn append(&'arena self, new_child: &'arena Self) {
         new_child.detach();
         new_child.parent.set(Some(self));
@@ -219,6 +489,36 @@ impl<'arena> Node<'arena> {
         self.previous_sibling.set(Some(new_sibling));
     }
 
+    /// Iterates over the nodes under this one in document order, not including this one.
+    pub fn descendants(&'arena self) -> Descendants<'arena> {
+        Descendants {
+            root: self,
+            next: self.first_child.get(),
+        }
+    }
+
+    /// Iterates over the elements under this one that match any of the comma-separated
+    /// `selectors`, in document order.
+    ///
+    /// Panics if `selectors` can't be parsed.
+    pub fn select(&'arena self, selectors: &str) -> impl Iterator<Item = Ref<'arena>> {
+        let selectors = parse_selectors(selectors);
+        self.descendants().filter(move |node| {
+            selectors
+                .iter()
+                .any(|selector| matches_css_selector(selector, node))
+        })
+    }
+
+    /// Whether this node is an element matching any of the comma-separated `selectors`.
+    ///
+    /// Panics if `selectors` can't be parsed.
+    pub fn matches(&'arena self, selectors: &str) -> bool {
+        parse_selectors(selectors)
+            .iter()
+            .any(|selector| matches_css_selector(selector, self))
+    }
+
     pub fn insert_after(&'arena self, new_sibling: &'arena Self) {
         new_sibling.detach();
         new_sibling.parent.set(self.parent.get());
@@ -238,6 +538,63 @@ impl<'arena> Node<'arena> {
     }
 }
 
+/// Iterator returned by `Node::descendants`. The next node is found when the current one is
+/// returned, so detaching the current node while iterating ends the iteration early; collect the
+/// nodes first to change the tree.
+pub struct Descendants<'arena> {
+    root: Ref<'arena>,
+    next: Option<Ref<'arena>>,
+}
+
+impl<'arena> Iterator for Descendants<'arena> {
+    type Item = Ref<'arena>;
+
+    fn next(&mut self) -> Option<Ref<'arena>> {
+        let node = self.next?;
+        self.next = node.first_child.get().or_else(|| {
+            let mut ancestor = node;
+            while !ptr::eq(ancestor, self.root) {
+                if let Some(sibling) = ancestor.next_sibling.get() {
+                    return Some(sibling);
+                }
+                ancestor = ancestor.parent.get()?;
+            }
+            None
+        });
+        Some(node)
+    }
+}
+
+/// Iterator returned by `Node::children`.
+pub struct Siblings<'arena> {
+    next: Option<Ref<'arena>>,
+}
+
+impl<'arena> Iterator for Siblings<'arena> {
+    type Item = Ref<'arena>;
+
+    fn next(&mut self) -> Option<Ref<'arena>> {
+        let node = self.next?;
+        self.next = node.next_sibling.get();
+        Some(node)
+    }
+}
+
+/// Iterator returned by `Node::ancestors`.
+pub struct Ancestors<'arena> {
+    next: Option<Ref<'arena>>,
+}
+
+impl<'arena> Iterator for Ancestors<'arena> {
+    type Item = Ref<'arena>;
+
+    fn next(&mut self) -> Option<Ref<'arena>> {
+        let node = self.next?;
+        self.next = node.parent.get();
+        Some(node)
+    }
+}
+
 impl<'arena> fmt::Display for Node<'arena> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write_node(self, 0, f)
@@ -254,7 +611,7 @@ impl<'arena> fmt::Display for NodeData<'arena> {
                 "Text: {}...",
                 &contents.borrow().chars().take(10).collect::<String>()
             ),
-            NodeData::StyleSheet { rules } => write!(
+            NodeData::StyleSheet { rules, .. } => write!(
                 f,
                 "Stylesheet: {}...",
                 &serialize_css_rules(rules)
@@ -532,6 +889,7 @@ impl<'arena> Serialize for Node<'arena> {
                 &NodeData::Element {
                     ref name,
                     ref attrs,
+                    ref template_contents,
                     ..
                 },
             ) => {
@@ -541,8 +899,11 @@ impl<'arena> Serialize for Node<'arena> {
                         attrs.borrow_mut().iter_mut().map(|at| match at {
                             Attribute::Style(at) => {
                                 if at.serialized_value.is_none() {
-                                    let serialized_declaration =
-                                        serialize_css_declarations(&at.value);
+                                    let serialized_declaration = serialize_css_style_attribute(
+                                        &at.value,
+                                        &at.source,
+                                        at.serialization,
+                                    );
                                     at.serialized_value = Some(serialized_declaration);
                                 }
 
@@ -557,9 +918,13 @@ impl<'arena> Serialize for Node<'arena> {
                     )?;
                 }
 
-                if let Some(child) = self.first_child.get() {
-                    child.serialize(serializer, IncludeNode)?;
-                }
+                let first_child = if let Some(contents) = template_contents {
+                    contents.first_child.get()
+                } else {
+                    self.first_child.get()
+                };
+
+                serialize_children(first_child, serializer)?;
 
                 if traversal_scope == IncludeNode {
                     serializer.end_elem(name.clone())?;
@@ -567,9 +932,7 @@ impl<'arena> Serialize for Node<'arena> {
             }
 
             (&ChildrenOnly(_), &NodeData::Document) => {
-                if let Some(child) = self.first_child.get() {
-                    child.serialize(serializer, IncludeNode)?;
-                }
+                serialize_children(self.first_child.get(), serializer)?
             }
 
             (&ChildrenOnly(_), _) => {}
@@ -580,8 +943,18 @@ impl<'arena> Serialize for Node<'arena> {
             (&IncludeNode, &NodeData::Text { ref contents }) => {
                 serializer.write_text(&contents.borrow())?
             }
-            (&IncludeNode, &NodeData::StyleSheet { ref rules }) => {
-                serializer.write_text(&serialize_css_rules(rules))?
+            (
+                &IncludeNode,
+                &NodeData::StyleSheet {
+                    ref rules,
+                    ref source,
+                    serialization,
+                },
+            ) => {
+                // CSS escapes can decode to a literal `</style>` that would end the raw text of the
+                // `<style>` element early, so escape the slash (`\/` is still `/` to CSS).
+                let css = serialize_css_stylesheet(rules, source, serialization);
+                serializer.write_text(&css.replace("</", "<\\/"))?
             }
             (&IncludeNode, &NodeData::Comment { ref contents }) => {
                 serializer.write_comment(&contents)?
@@ -596,10 +969,18 @@ impl<'arena> Serialize for Node<'arena> {
             (&IncludeNode, &NodeData::Document) => panic!("Can't serialize Document node itself"),
         }
 
-        if let Some(sibling) = self.next_sibling.get() {
-            sibling.serialize(serializer, IncludeNode)?
-        }
-
         Ok(())
     }
 }
+
+fn serialize_children<'arena, S: Serializer>(
+    first_child: Option<Ref<'arena>>,
+    serializer: &mut S,
+) -> io::Result<()> {
+    let mut child = first_child;
+    while let Some(node) = child {
+        node.serialize(serializer, IncludeNode)?;
+        child = node.next_sibling.get();
+    }
+    Ok(())
+}
@@ -1,21 +1,48 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::sanitizer::{ContentWhitespace, SanitizerConfig};
+use crate::css_parser::CssSerialization;
+use crate::css_value::CssVarPolicy;
+use crate::sanitizer::{ContentWhitespace, ElementDisposition, SanitizerConfig};
 
 lazy_static! {
     pub static ref DEFAULT_CONFIG: SanitizerConfig = SanitizerConfig {
         allow_comments: false,
         allow_doctype: false,
         allowed_elements: HashSet::new(),
+        rename_elements: HashMap::new(),
         allowed_attributes: HashSet::new(),
         allowed_attributes_per_element: HashMap::new(),
         add_attributes: HashMap::new(),
         add_attributes_per_element: HashMap::new(),
         allowed_protocols: HashMap::new(),
+        allowed_svg_elements: HashSet::new(),
+        allowed_svg_attributes: HashSet::new(),
+        allowed_svg_protocols: HashMap::new(),
+        allowed_mathml_elements: HashSet::new(),
+        allowed_mathml_attributes: HashSet::new(),
         allowed_css_at_rules: HashSet::new(),
         allowed_css_properties: HashSet::new(),
+        allowed_css_custom_property_prefixes: HashSet::new(),
+        css_var_policy: CssVarPolicy::Remove,
+        allowed_css_values: HashMap::new(),
+        rewrite_css_values: vec![],
+        rewrite_css_values_per_property: HashMap::new(),
         allowed_css_protocols: HashSet::new(),
+        allowed_css_hosts: None,
+        allowed_css_media_types: HashSet::new(),
+        allowed_css_media_features: HashSet::new(),
         allow_css_comments: false,
+        allowed_css_selector_attributes: HashSet::new(),
+        allow_css_universal_selector: false,
+        allow_css_root_selectors: false,
+        css_selector_scope: None,
+        id_prefix: None,
+        class_prefix: None,
+        animation_name_prefix: None,
+        css_serialization: CssSerialization::Normalized,
+        inline_css: false,
+        disallowed_element_disposition: ElementDisposition::Unwrap,
+        disallowed_element_disposition_per_element: HashMap::new(),
         remove_contents_when_unwrapped: hashset! {
             local_name!("iframe"),
             local_name!("noembed"),
@@ -50,6 +77,9 @@ lazy_static! {
             local_name!("pre") => ContentWhitespace::space_around(),
             local_name!("section") => ContentWhitespace::space_around(),
             local_name!("ul") => ContentWhitespace::space_around(),
-        }
+        },
+        remove_empty_elements: HashSet::new(),
+        remove_whitespace_only_elements: false,
+        round_trip_check: false,
     };
 }
@@ -0,0 +1,78 @@
+use crate::config::relaxed::RELAXED_CONFIG;
+use crate::sanitizer::SanitizerConfig;
+
+lazy_static! {
+    pub static ref MATHML_CONFIG: SanitizerConfig = {
+        let mut config = RELAXED_CONFIG.clone();
+        config.allowed_mathml_elements.extend(hashset! {
+            local_name!("annotation"),
+            local_name!("math"),
+            local_name!("menclose"),
+            local_name!("merror"),
+            local_name!("mfenced"),
+            local_name!("mfrac"),
+            local_name!("mi"),
+            local_name!("mmultiscripts"),
+            local_name!("mn"),
+            local_name!("mo"),
+            local_name!("mover"),
+            local_name!("mpadded"),
+            local_name!("mphantom"),
+            local_name!("mprescripts"),
+            local_name!("mroot"),
+            local_name!("mrow"),
+            local_name!("ms"),
+            local_name!("mspace"),
+            local_name!("msqrt"),
+            local_name!("mstyle"),
+            local_name!("msub"),
+            local_name!("msubsup"),
+            local_name!("msup"),
+            local_name!("mtable"),
+            local_name!("mtd"),
+            local_name!("mtext"),
+            local_name!("mtr"),
+            local_name!("munder"),
+            local_name!("munderover"),
+            local_name!("none"),
+            local_name!("semantics"),
+        });
+        config.allowed_mathml_attributes.extend(hashset! {
+            local_name!("accent"),
+            local_name!("accentunder"),
+            local_name!("close"),
+            local_name!("columnalign"),
+            local_name!("columnlines"),
+            local_name!("columnspan"),
+            local_name!("depth"),
+            local_name!("dir"),
+            local_name!("display"),
+            local_name!("displaystyle"),
+            local_name!("encoding"),
+            local_name!("fence"),
+            local_name!("frame"),
+            local_name!("height"),
+            local_name!("largeop"),
+            local_name!("linethickness"),
+            local_name!("lspace"),
+            local_name!("mathsize"),
+            local_name!("mathvariant"),
+            local_name!("maxsize"),
+            local_name!("minsize"),
+            local_name!("movablelimits"),
+            local_name!("notation"),
+            local_name!("open"),
+            local_name!("rowalign"),
+            local_name!("rowlines"),
+            local_name!("rowspan"),
+            local_name!("rspace"),
+            local_name!("scriptlevel"),
+            local_name!("separator"),
+            local_name!("separators"),
+            local_name!("stretchy"),
+            local_name!("symmetric"),
+            local_name!("width"),
+        });
+        config
+    };
+}
@@ -1,4 +1,6 @@
 pub mod basic;
 pub mod default;
+pub mod mathml;
 pub mod relaxed;
 pub mod restricted;
+pub mod svg;
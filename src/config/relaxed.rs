@@ -1,6 +1,7 @@
 use html5ever::LocalName;
 
 use crate::config::basic::BASIC_CONFIG;
+use crate::css_value::CssValueType;
 use crate::sanitizer::{Protocol, SanitizerConfig};
 
 lazy_static! {
@@ -607,6 +608,7 @@ lazy_static! {
             css_property!("flow-from"),
             css_property!("flow-into"),
             css_property!("font"),
+            css_property!("font-display"),
             css_property!("font-family"),
             css_property!("font-feature-settings"),
             css_property!("font-kerning"),
@@ -768,6 +770,7 @@ lazy_static! {
             css_property!("speak-numeral"),
             css_property!("speak-punctuation"),
             css_property!("speech-rate"),
+            css_property!("src"),
             css_property!("stop-color"),
             css_property!("stop-opacity"),
             css_property!("stress"),
@@ -843,11 +846,77 @@ lazy_static! {
             css_property!("writing-mode"),
             css_property!("z-index"),
         });
+        config.allowed_css_values.extend(hashmap! {
+            css_property!("height") => hashset! {
+                CssValueType::Keyword("auto"),
+                CssValueType::Length,
+                CssValueType::Percentage,
+                CssValueType::Function("calc"),
+            },
+            css_property!("position") => hashset! {
+                CssValueType::Keyword("absolute"),
+                CssValueType::Keyword("relative"),
+                CssValueType::Keyword("static"),
+            },
+            css_property!("width") => hashset! {
+                CssValueType::Keyword("auto"),
+                CssValueType::Length,
+                CssValueType::Percentage,
+                CssValueType::Function("calc"),
+            },
+            css_property!("z-index") => hashset! {
+                CssValueType::Keyword("auto"),
+                CssValueType::Integer,
+            },
+        });
         config.allowed_css_protocols.extend(hashset! {
             Protocol::Scheme("http"),
             Protocol::Scheme("https"),
             Protocol::Relative,
         });
+        config.allowed_css_media_types.extend(hashset! {
+            "all",
+            "print",
+            "screen",
+        });
+        config.allowed_css_media_features.extend(hashset! {
+            "any-hover",
+            "any-pointer",
+            "aspect-ratio",
+            "color",
+            "color-gamut",
+            "height",
+            "hover",
+            "max-aspect-ratio",
+            "max-height",
+            "max-resolution",
+            "max-width",
+            "min-aspect-ratio",
+            "min-height",
+            "min-resolution",
+            "min-width",
+            "monochrome",
+            "orientation",
+            "pointer",
+            "prefers-color-scheme",
+            "prefers-contrast",
+            "prefers-reduced-motion",
+            "resolution",
+            "width",
+        });
+        config.allowed_css_selector_attributes.extend(hashset! {
+            local_name!("alt"),
+            local_name!("class"),
+            local_name!("dir"),
+            local_name!("hidden"),
+            local_name!("href"),
+            local_name!("hreflang"),
+            local_name!("lang"),
+            local_name!("rel"),
+            local_name!("title"),
+            local_name!("type"),
+        });
+        config.allow_css_universal_selector = true;
         config
     };
 }
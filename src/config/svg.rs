@@ -0,0 +1,114 @@
+use crate::config::relaxed::RELAXED_CONFIG;
+use crate::sanitizer::{Protocol, SanitizerConfig};
+
+lazy_static! {
+    pub static ref SVG_CONFIG: SanitizerConfig = {
+        let mut config = RELAXED_CONFIG.clone();
+        config.allowed_svg_elements.extend(hashset! {
+            local_name!("a"),
+            local_name!("circle"),
+            local_name!("clipPath"),
+            local_name!("defs"),
+            local_name!("desc"),
+            local_name!("ellipse"),
+            local_name!("g"),
+            local_name!("line"),
+            local_name!("linearGradient"),
+            local_name!("marker"),
+            local_name!("mask"),
+            local_name!("path"),
+            local_name!("pattern"),
+            local_name!("polygon"),
+            local_name!("polyline"),
+            local_name!("radialGradient"),
+            local_name!("rect"),
+            local_name!("stop"),
+            local_name!("svg"),
+            local_name!("symbol"),
+            local_name!("text"),
+            local_name!("textPath"),
+            local_name!("title"),
+            local_name!("tspan"),
+        });
+        config.allowed_svg_attributes.extend(hashset! {
+            local_name!("class"),
+            local_name!("clip-path"),
+            local_name!("clip-rule"),
+            local_name!("color"),
+            local_name!("cx"),
+            local_name!("cy"),
+            local_name!("d"),
+            local_name!("display"),
+            local_name!("dx"),
+            local_name!("dy"),
+            local_name!("fill"),
+            local_name!("fill-opacity"),
+            local_name!("fill-rule"),
+            local_name!("font-family"),
+            local_name!("font-size"),
+            local_name!("font-style"),
+            local_name!("font-weight"),
+            local_name!("gradientTransform"),
+            local_name!("gradientUnits"),
+            local_name!("height"),
+            local_name!("href"),
+            local_name!("id"),
+            local_name!("marker-end"),
+            local_name!("marker-mid"),
+            local_name!("marker-start"),
+            local_name!("markerHeight"),
+            local_name!("markerWidth"),
+            local_name!("mask"),
+            local_name!("offset"),
+            local_name!("opacity"),
+            local_name!("orient"),
+            local_name!("patternContentUnits"),
+            local_name!("patternTransform"),
+            local_name!("patternUnits"),
+            local_name!("points"),
+            local_name!("preserveAspectRatio"),
+            local_name!("r"),
+            local_name!("refX"),
+            local_name!("refY"),
+            local_name!("rx"),
+            local_name!("ry"),
+            local_name!("startOffset"),
+            local_name!("stop-color"),
+            local_name!("stop-opacity"),
+            local_name!("stroke"),
+            local_name!("stroke-dasharray"),
+            local_name!("stroke-dashoffset"),
+            local_name!("stroke-linecap"),
+            local_name!("stroke-linejoin"),
+            local_name!("stroke-miterlimit"),
+            local_name!("stroke-opacity"),
+            local_name!("stroke-width"),
+            local_name!("text-anchor"),
+            local_name!("transform"),
+            local_name!("viewBox"),
+            local_name!("visibility"),
+            local_name!("width"),
+            local_name!("x"),
+            local_name!("x1"),
+            local_name!("x2"),
+            local_name!("y"),
+            local_name!("y1"),
+            local_name!("y2"),
+        });
+        // SVG attribute names are matched by their local name, so this covers both `href` and
+        // `xlink:href`.
+        config.allowed_svg_protocols.extend(hashmap! {
+            local_name!("href") => hashset! {
+                Protocol::Scheme("http"),
+                Protocol::Scheme("https"),
+                Protocol::Scheme("mailto"),
+                Protocol::Relative,
+            },
+        });
+        // HTML inside `<foreignObject>` would otherwise be hoisted into SVG content.
+        config
+            .remove_contents_when_unwrapped
+            .insert(local_name!("foreignObject"));
+        config
+    };
+}
@@ -0,0 +1,158 @@
+use cssparser::{BasicParseError, Delimiter, ParseError, Parser, Token};
+
+use crate::css_parser::{parse_css_declaration_value, CssDeclaration, CssSpan};
+
+/// One query from the comma-separated list in an `@media` prelude.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CssMediaQuery {
+    pub media_type: Option<String>,
+    pub features: Vec<String>,
+    pub query: String,
+}
+
+/// What an `@supports` condition tests for: declarations like `(display: grid)` and selectors like
+/// `selector(a > b)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CssSupportsCondition {
+    pub declarations: Vec<CssDeclaration>,
+    pub selectors: Vec<String>,
+    pub condition: String,
+}
+
+type ConditionParseError<'i> = ParseError<'i, ()>;
+
+/// Parses an `@media` prelude, dropping any query that can't be parsed. Media type and feature
+/// names are lowercased.
+pub fn parse_css_media_query_list(parser: &mut Parser) -> Vec<CssMediaQuery> {
+    let mut queries = Vec::new();
+    if parser.is_exhausted() {
+        return queries;
+    }
+    loop {
+        let start = parser.position();
+        let result = parser.parse_until_before(Delimiter::Comma, parse_media_query);
+        // Media queries that don't parse are dropped.
+        if let Ok(mut query) = result {
+            query.query = parser.slice_from(start).trim().to_string();
+            queries.push(query);
+        }
+        if parser.next().is_err() {
+            break;
+        }
+    }
+    queries
+}
+
+fn parse_media_query<'i>(
+    input: &mut Parser<'i, '_>,
+) -> Result<CssMediaQuery, ConditionParseError<'i>> {
+    let mut query = CssMediaQuery::default();
+    while let Ok(token) = input.next() {
+        match token.clone() {
+            Token::Ident(ident) => {
+                if !is_condition_keyword(&ident) {
+                    if query.media_type.is_some() || !query.features.is_empty() {
+                        return Err(input.new_custom_error(()));
+                    }
+                    query.media_type = Some(ident.to_ascii_lowercase());
+                }
+            }
+            Token::ParenthesisBlock => {
+                input.parse_nested_block(|input| parse_media_in_parens(input, &mut query))?
+            }
+            token => return Err(input.new_unexpected_token_error(token)),
+        }
+    }
+    Ok(query)
+}
+
+// Parses either a nested condition like `(not (color))` or a single media feature like
+// `(max-width: 600px)` or `(400px <= width <= 700px)`.
+fn parse_media_in_parens<'i>(
+    input: &mut Parser<'i, '_>,
+    query: &mut CssMediaQuery,
+) -> Result<(), ConditionParseError<'i>> {
+    let mut seen_colon = false;
+    while let Ok(token) = input.next() {
+        match token.clone() {
+            Token::ParenthesisBlock => {
+                input.parse_nested_block(|input| parse_media_in_parens(input, query))?
+            }
+            Token::Ident(ident) => {
+                // Anything after the colon is the feature's value, e.g. `dark`.
+                if !seen_colon && !is_condition_keyword(&ident) {
+                    query.features.push(ident.to_ascii_lowercase());
+                }
+            }
+            Token::Colon if !seen_colon => seen_colon = true,
+            Token::Number { .. } | Token::Dimension { .. } | Token::Percentage { .. } => {}
+            Token::Delim('<') | Token::Delim('>') | Token::Delim('=') | Token::Delim('/') => {}
+            token => return Err(input.new_unexpected_token_error(token)),
+        }
+    }
+    Ok(())
+}
+
+/// Parses an `@supports` prelude, returning `None` if it isn't a condition we understand.
+pub fn parse_css_supports_condition(parser: &mut Parser) -> Option<CssSupportsCondition> {
+    let start = parser.position();
+    let mut condition = CssSupportsCondition::default();
+    match parse_supports_condition(parser, &mut condition) {
+        Ok(()) => {
+            condition.condition = parser.slice_from(start).trim().to_string();
+            Some(condition)
+        }
+        Err(_) => None,
+    }
+}
+
+fn parse_supports_condition<'i>(
+    input: &mut Parser<'i, '_>,
+    condition: &mut CssSupportsCondition,
+) -> Result<(), ConditionParseError<'i>> {
+    while let Ok(token) = input.next() {
+        match token.clone() {
+            Token::Ident(ref ident) if is_condition_keyword(ident) => {}
+            Token::ParenthesisBlock => input.parse_nested_block(|input| {
+                let start = input.state();
+                let property = input.try_parse(|input| -> Result<_, BasicParseError<'i>> {
+                    let property = input.expect_ident()?.to_string();
+                    input.expect_colon()?;
+                    Ok(property)
+                });
+                match property {
+                    Ok(property) => {
+                        let (value, important) = parse_css_declaration_value(input);
+                        condition.declarations.push(CssDeclaration {
+                            property,
+                            value,
+                            important,
+                            span: CssSpan::since(&start, input),
+                        });
+                        Ok(())
+                    }
+                    Err(_) => parse_supports_condition(input, condition),
+                }
+            })?,
+            Token::Function(ref name) if name.eq_ignore_ascii_case("selector") => {
+                let start = input.position();
+                input.parse_nested_block(|input| -> Result<(), ConditionParseError<'i>> {
+                    while input.next().is_ok() {}
+                    Ok(())
+                })?;
+                let selector = input.slice_from(start);
+                condition
+                    .selectors
+                    .push(selector.strip_suffix(')').unwrap_or(selector).to_string());
+            }
+            token => return Err(input.new_unexpected_token_error(token)),
+        }
+    }
+    Ok(())
+}
+
+fn is_condition_keyword(ident: &str) -> bool {
+    ["and", "not", "only", "or"]
+        .iter()
+        .any(|keyword| keyword.eq_ignore_ascii_case(ident))
+}
@@ -1,44 +1,173 @@
 use cssparser::{
-    AtRuleParser, AtRuleType, CowRcStr, DeclarationListParser, DeclarationParser, ParseError,
-    Parser, ParserInput, QualifiedRuleParser, RuleListParser, SourceLocation, ToCss,
-    TokenSerializationType,
+    serialize_identifier, AtRuleParser, AtRuleType, BasicParseError, CowRcStr,
+    DeclarationListParser, DeclarationParser, ParseError, Parser, ParserInput, ParserState,
+    QualifiedRuleParser, RuleListParser, SourceLocation, ToCss, Token, TokenSerializationType,
 };
 use std::convert::Into;
 use std::error::Error;
 use std::fmt;
+use std::ops::Range;
 
-#[derive(Debug)]
+use crate::css_condition::{
+    parse_css_media_query_list, parse_css_supports_condition, CssMediaQuery, CssSupportsCondition,
+};
+
+/// Where a node starts and ends in the source CSS, with lines counted from 0 and columns from 1 as
+/// in cssparser's `SourceLocation`. Rules and declarations end after their closing `}` or `;`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CssSpan {
+    pub start: SourceLocation,
+    pub end: SourceLocation,
+    /// The byte offsets of `start` and `end` in the source.
+    pub start_index: usize,
+    pub end_index: usize,
+}
+
+impl CssSpan {
+    /// The span from `start` to where `input` is now.
+    pub fn since(start: &ParserState, input: &Parser) -> CssSpan {
+        CssSpan {
+            start: start.source_location(),
+            end: input.current_source_location(),
+            start_index: start.position().byte_index(),
+            end_index: input.position().byte_index(),
+        }
+    }
+
+    fn at(input: &Parser) -> CssSpan {
+        CssSpan::since(&input.state(), input)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum CssRule {
     AtRule(CssAtRule),
     StyleRule(CssStyleRule),
 }
 
-// TODO: try to use CowRcStr instead of Strings in these structs.
-// I tried to do it earlier but I ended up in lifetime hell in parse_block().
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct CssStyleRule {
     pub selectors: String,
     pub declarations: Vec<CssDeclaration>,
+    pub span: CssSpan,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct CssAtRule {
     // TODO: put name into the string cache
     pub name: String,
-    pub prelude: String,
-    pub block: Option<Vec<CssRule>>,
+    pub prelude: CssAtRulePrelude,
+    pub block: Option<CssAtRuleBlock>,
+    pub span: CssSpan,
 }
 
-#[derive(Debug)]
+/// The prelude of an at-rule, parsed for the at-rules the sanitizer needs to look inside of.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CssAtRulePrelude {
+    /// `@import "url" <conditions>`, where the conditions are kept as they were written.
+    Import {
+        url: String,
+        conditions: String,
+    },
+    Media(Vec<CssMediaQuery>),
+    Supports(CssSupportsCondition),
+    Keyframes {
+        name: String,
+        quoted: bool,
+    },
+    /// Any other prelude, or one of the above that couldn't be parsed, as serialized tokens.
+    Other(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CssAtRuleBlock {
+    Rules(Vec<CssRule>),
+    // At-rules like `@font-face` and `@page` contain descriptors rather than nested rules.
+    Declarations(Vec<CssDeclaration>),
+    Keyframes(Vec<CssKeyframe>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CssKeyframe {
+    pub selectors: String,
+    pub declarations: Vec<CssDeclaration>,
+    pub span: CssSpan,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct CssDeclaration {
     pub property: String,
-    pub value: String,
+    pub value: CssValue,
+    pub important: bool,
+    pub span: CssSpan,
 }
 
-#[derive(Debug)]
-struct CssAtRulePrelude {
-    name: String,
-    prelude: String,
+/// A declaration value as a list of component values, without any trailing `!important`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CssValue(pub Vec<CssComponentValue>);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CssComponentValue {
+    Token(Token<'static>),
+    WhiteSpace(String),
+    Comment(String),
+    /// A function or a `(`, `[` or `{` block, with the component values inside it.
+    Block(Token<'static>, Vec<CssComponentValue>),
+}
+
+/// How sanitized CSS is written back out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CssSerialization {
+    /// Rebuilds the CSS from the parsed rules.
+    Normalized,
+    /// Copies the source text of every rule and declaration the sanitizer left alone, including
+    /// the whitespace and comments between them, and only rebuilds the ones it changed.
+    Preserved,
+    /// Rebuilds the CSS without comments or any whitespace that isn't needed.
+    Minified,
+}
+
+// Lets `parse_rule_list` set the spans of the rules it collects, and the preserving serializer
+// match sanitized nodes up with the source they came from.
+trait Spanned {
+    fn span(&self) -> CssSpan;
+    fn span_mut(&mut self) -> &mut CssSpan;
+}
+
+impl Spanned for CssRule {
+    fn span(&self) -> CssSpan {
+        match self {
+            CssRule::AtRule(at_rule) => at_rule.span,
+            CssRule::StyleRule(style_rule) => style_rule.span,
+        }
+    }
+
+    fn span_mut(&mut self) -> &mut CssSpan {
+        match self {
+            CssRule::AtRule(at_rule) => &mut at_rule.span,
+            CssRule::StyleRule(style_rule) => &mut style_rule.span,
+        }
+    }
+}
+
+impl Spanned for CssKeyframe {
+    fn span(&self) -> CssSpan {
+        self.span
+    }
+
+    fn span_mut(&mut self) -> &mut CssSpan {
+        &mut self.span
+    }
+}
+
+impl Spanned for CssDeclaration {
+    fn span(&self) -> CssSpan {
+        self.span
+    }
+
+    fn span_mut(&mut self) -> &mut CssSpan {
+        &mut self.span
+    }
 }
 
 #[derive(Debug)]
@@ -49,8 +178,8 @@ pub type CssParseError<'i> = ParseError<'i, CssError>;
 struct CssParser;
 
 impl<'i> AtRuleParser<'i> for CssParser {
-    type PreludeBlock = CssAtRulePrelude;
-    type PreludeNoBlock = CssAtRulePrelude;
+    type PreludeBlock = (String, CssAtRulePrelude);
+    type PreludeNoBlock = (String, CssAtRulePrelude);
     type AtRule = CssRule;
     type Error = CssError;
 
@@ -59,68 +188,67 @@ impl<'i> AtRuleParser<'i> for CssParser {
         name: CowRcStr<'i>,
         input: &mut Parser<'i, 't>,
     ) -> Result<AtRuleType<Self::PreludeNoBlock, Self::PreludeBlock>, CssParseError<'i>> {
-        let mut prelude = String::new();
-        let mut previous_token = TokenSerializationType::nothing();
-        while let Ok(token) = input.next_including_whitespace_and_comments() {
-            let token_type = token.serialization_type();
-            if previous_token.needs_separator_when_before(token_type) {
-                prelude.push_str("/**/");
-            }
-            previous_token = token_type;
-            token.to_css(&mut prelude).unwrap();
-        }
+        let prelude = parse_at_rule_prelude(&name, input);
         match_ignore_ascii_case! { &*name,
             "import" | "namespace" | "charset" => {
-                Ok(AtRuleType::WithoutBlock(CssAtRulePrelude {
-                    name: name.to_string(),
-                    prelude,
-                }))
+                Ok(AtRuleType::WithoutBlock((name.to_string(), prelude)))
             },
             _ => {
-                Ok(AtRuleType::WithBlock(CssAtRulePrelude {
-                    name: name.to_string(),
-                    prelude,
-                }))
+                Ok(AtRuleType::WithBlock((name.to_string(), prelude)))
             }
         }
     }
 
     fn parse_block<'t>(
         &mut self,
-        prelude: Self::PreludeBlock,
+        (name, prelude): Self::PreludeBlock,
         _location: SourceLocation,
         input: &mut Parser<'i, 't>,
     ) -> Result<Self::AtRule, CssParseError<'i>> {
-        let rule_list_parser = RuleListParser::new_for_stylesheet(input, CssParser);
-        let mut rules = Vec::new();
-
-        for result in rule_list_parser {
-            let rule = match result {
-                Ok(r) => r,
-                Err((error, string)) => {
-                    eprintln!("Rule dropped: {:?}, {:?}", error, string);
-                    continue;
-                }
-            };
-            rules.push(rule);
-        }
+        let block = match_ignore_ascii_case! { &name,
+            "bottom-center" | "bottom-left" | "bottom-left-corner" | "bottom-right"
+                | "bottom-right-corner" | "counter-style" | "font-face" | "left-bottom"
+                | "left-middle" | "left-top" | "page" | "right-bottom" | "right-middle"
+                | "right-top" | "top-center" | "top-left" | "top-left-corner" | "top-right"
+                | "top-right-corner" | "viewport" => {
+                CssAtRuleBlock::Declarations(parse_declarations(input).unwrap())
+            },
+            "keyframes" | "-moz-keyframes" | "-o-keyframes" | "-webkit-keyframes" => {
+                CssAtRuleBlock::Keyframes(parse_rule_list(
+                    RuleListParser::new_for_nested_rule(input, CssKeyframesParser),
+                ))
+            },
+            _ => {
+                CssAtRuleBlock::Rules(parse_rule_list(
+                    RuleListParser::new_for_stylesheet(input, CssParser),
+                ))
+            }
+        };
 
         Ok(CssRule::AtRule(CssAtRule {
-            name: prelude.name,
-            prelude: prelude.prelude,
-            block: Some(rules),
+            name,
+            prelude,
+            block: Some(block),
+            span: CssSpan::at(input),
         }))
     }
 
     fn rule_without_block(
         &mut self,
-        prelude: Self::PreludeNoBlock,
-        _location: SourceLocation,
+        (name, prelude): Self::PreludeNoBlock,
+        location: SourceLocation,
     ) -> Self::AtRule {
         CssRule::AtRule(CssAtRule {
-            name: prelude.name,
-            prelude: prelude.prelude,
+            name,
+            prelude,
             block: None,
+            // `parse_rule_list` fills in the rest.
+            span: CssSpan {
+                start: location,
+                end: location,
+                start_index: 0,
+                end_index: 0,
+            },
         })
     }
 }
@@ -135,15 +263,7 @@ impl<'i> QualifiedRuleParser<'i> for CssParser {
         input: &mut Parser<'i, 't>,
     ) -> Result<Self::Prelude, CssParseError<'i>> {
         let mut prelude = String::new();
-        let mut previous_token = TokenSerializationType::nothing();
-        while let Ok(token) = input.next_including_whitespace_and_comments() {
-            let token_type = token.serialization_type();
-            if previous_token.needs_separator_when_before(token_type) {
-                prelude.push_str("/**/");
-            }
-            previous_token = token_type;
-            token.to_css(&mut prelude).unwrap();
-        }
+        serialize_tokens(input, &mut prelude, &mut |token, _| Some(token));
         Ok(prelude)
     }
 
@@ -156,30 +276,313 @@ impl<'i> QualifiedRuleParser<'i> for CssParser {
         Ok(CssRule::StyleRule(CssStyleRule {
             selectors,
             declarations: parse_declarations(input).unwrap(),
+            span: CssSpan::at(input),
         }))
     }
 }
 
-pub fn parse_css_stylesheet(css: &str) -> Vec<CssRule> {
-    let mut parser_input = ParserInput::new(css);
-    let mut parser = Parser::new(&mut parser_input);
+// Parses the preludes of the at-rules that `CssAtRulePrelude` has a variant for. Anything else, or
+// anything that doesn't parse, is kept as serialized tokens.
+fn parse_at_rule_prelude<'i>(name: &str, input: &mut Parser<'i, '_>) -> CssAtRulePrelude {
+    let start = input.state();
+    let prelude = match_ignore_ascii_case! { name,
+        "import" => parse_import_prelude(input).ok(),
+        "media" => Some(CssAtRulePrelude::Media(parse_css_media_query_list(input))),
+        "supports" => parse_css_supports_condition(input).map(CssAtRulePrelude::Supports),
+        "keyframes" | "-moz-keyframes" | "-o-keyframes" | "-webkit-keyframes" => {
+            parse_keyframes_prelude(input).ok()
+        },
+        _ => None,
+    };
+
+    // The typed parsers may stop early, but the whole prelude has to be consumed.
+    input.reset(&start);
+    match prelude {
+        Some(prelude) => {
+            while input.next().is_ok() {}
+            prelude
+        }
+        None => {
+            let mut prelude = String::new();
+            serialize_tokens(input, &mut prelude, &mut |token, _| Some(token));
+            CssAtRulePrelude::Other(prelude.trim().to_string())
+        }
+    }
+}
+
+fn parse_import_prelude<'i>(
+    input: &mut Parser<'i, '_>,
+) -> Result<CssAtRulePrelude, BasicParseError<'i>> {
+    let url = input.expect_url_or_string()?.to_string();
+    let conditions_start = input.position();
+    while input.next().is_ok() {}
+    Ok(CssAtRulePrelude::Import {
+        url,
+        conditions: input.slice_from(conditions_start).trim().to_string(),
+    })
+}
+
+fn parse_keyframes_prelude<'i>(
+    input: &mut Parser<'i, '_>,
+) -> Result<CssAtRulePrelude, BasicParseError<'i>> {
+    let prelude = match input.next()?.clone() {
+        Token::Ident(name) => CssAtRulePrelude::Keyframes {
+            name: name.to_string(),
+            quoted: false,
+        },
+        Token::QuotedString(name) => CssAtRulePrelude::Keyframes {
+            name: name.to_string(),
+            quoted: true,
+        },
+        token => return Err(input.new_basic_unexpected_token_error(token)),
+    };
+    input.expect_exhausted()?;
+    Ok(prelude)
+}
+
+impl ToCss for CssAtRulePrelude {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        match self {
+            CssAtRulePrelude::Import { url, conditions } => {
+                Token::QuotedString(url.as_str().into()).to_css(dest)?;
+                if !conditions.is_empty() {
+                    dest.write_char(' ')?;
+                    dest.write_str(conditions)?;
+                }
+                Ok(())
+            }
+            CssAtRulePrelude::Media(queries) => {
+                for (index, query) in queries.iter().enumerate() {
+                    if index != 0 {
+                        dest.write_str(", ")?;
+                    }
+                    dest.write_str(&query.query)?;
+                }
+                Ok(())
+            }
+            CssAtRulePrelude::Supports(condition) => dest.write_str(&condition.condition),
+            CssAtRulePrelude::Keyframes { name, quoted: true } => {
+                Token::QuotedString(name.as_str().into()).to_css(dest)
+            }
+            CssAtRulePrelude::Keyframes {
+                name,
+                quoted: false,
+            } => Token::Ident(name.as_str().into()).to_css(dest),
+            CssAtRulePrelude::Other(prelude) => dest.write_str(prelude),
+        }
+    }
+}
 
-    let rule_list_parser = RuleListParser::new_for_stylesheet(&mut parser, CssParser);
+struct CssKeyframesParser;
+
+impl<'i> QualifiedRuleParser<'i> for CssKeyframesParser {
+    type Prelude = String;
+    type QualifiedRule = CssKeyframe;
+    type Error = CssError;
+
+    // Keyframe selectors are a comma-separated list of `from`, `to` and percentages.
+    fn parse_prelude<'t>(
+        &mut self,
+        input: &mut Parser<'i, 't>,
+    ) -> Result<Self::Prelude, CssParseError<'i>> {
+        let mut selectors = Vec::new();
+        loop {
+            let location = input.current_source_location();
+            let token = input.next()?.clone();
+            match token {
+                Token::Ident(ref ident) if ident.eq_ignore_ascii_case("from") => {
+                    selectors.push("from".to_string())
+                }
+                Token::Ident(ref ident) if ident.eq_ignore_ascii_case("to") => {
+                    selectors.push("to".to_string())
+                }
+                Token::Percentage { unit_value, .. } if (0. ..=1.).contains(&unit_value) => {
+                    selectors.push(token.to_css_string())
+                }
+                token => return Err(location.new_unexpected_token_error(token)),
+            }
+            if input.is_exhausted() {
+                break;
+            }
+            input.expect_comma()?;
+        }
+        Ok(selectors.join(", "))
+    }
+
+    fn parse_block<'t>(
+        &mut self,
+        selectors: Self::Prelude,
+        _location: SourceLocation,
+        input: &mut Parser<'i, 't>,
+    ) -> Result<CssKeyframe, CssParseError<'i>> {
+        Ok(CssKeyframe {
+            selectors,
+            declarations: parse_declarations(input).unwrap(),
+            span: CssSpan::at(input),
+        })
+    }
+}
+
+impl<'i> AtRuleParser<'i> for CssKeyframesParser {
+    type PreludeBlock = ();
+    type PreludeNoBlock = ();
+    type AtRule = CssKeyframe;
+    type Error = CssError;
+}
 
+// Collects the rules that parse, spanning each from its first token to its closing `}` or `;`.
+fn parse_rule_list<'i, P, R>(mut rule_list_parser: RuleListParser<'i, '_, '_, P>) -> Vec<R>
+where
+    P: QualifiedRuleParser<'i, QualifiedRule = R, Error = CssError>
+        + AtRuleParser<'i, AtRule = R, Error = CssError>,
+    R: Spanned,
+{
     let mut rules = Vec::new();
+    loop {
+        // The location cssparser passes to at-rules is after their name, so take it from here.
+        loop {
+            rule_list_parser.input.skip_whitespace();
+            let skipped = rule_list_parser.input.try_parse(|input| {
+                match input.next_including_whitespace_and_comments() {
+                    Ok(Token::CDO) | Ok(Token::CDC) => Ok(()),
+                    _ => Err(()),
+                }
+            });
+            if skipped.is_err() {
+                break;
+            }
+        }
+        let start = rule_list_parser.input.state();
 
-    for result in rule_list_parser {
-        let rule = match result {
-            Ok(r) => r,
-            Err((error, string)) => {
-                eprintln!("Rule dropped: {:?}, {:?}", error, string);
+        match rule_list_parser.next() {
+            Some(Ok(mut rule)) => {
+                *rule.span_mut() = CssSpan::since(&start, rule_list_parser.input);
+                rules.push(rule);
+            }
+            Some(Err((error, string))) => eprintln!("Rule dropped: {:?}, {:?}", error, string),
+            None => break,
+        }
+    }
+    rules
+}
+
+// Writes out the remaining tokens of `input`, including the contents of any nested blocks, which
+// `next_including_whitespace_and_comments` would otherwise skip over. `rewrite` gets to replace
+// each token that doesn't open a block, or drop it by returning `None`. It is also given how many
+// blocks deep the token is.
+pub fn serialize_tokens<'i>(
+    input: &mut Parser<'i, '_>,
+    output: &mut String,
+    rewrite: &mut dyn FnMut(Token<'i>, usize) -> Option<Token<'i>>,
+) {
+    serialize_nested_tokens(input, output, rewrite, 0);
+}
+
+fn serialize_nested_tokens<'i>(
+    input: &mut Parser<'i, '_>,
+    output: &mut String,
+    rewrite: &mut dyn FnMut(Token<'i>, usize) -> Option<Token<'i>>,
+    depth: usize,
+) {
+    let mut previous_token = TokenSerializationType::nothing();
+    while let Ok(token) = input.next_including_whitespace_and_comments() {
+        let closing_token = closing_token(token);
+        let token = match closing_token {
+            Some(_) => token.clone(),
+            None => match rewrite(token.clone(), depth) {
+                Some(token) => token,
+                None => continue,
+            },
+        };
+
+        let token_type = token.serialization_type();
+        if previous_token.needs_separator_when_before(token_type) {
+            output.push_str("/**/");
+        }
+        previous_token = token_type;
+        token.to_css(output).unwrap();
+
+        if let Some(closing_token) = closing_token {
+            let _ = input.parse_nested_block(|input| -> Result<(), CssParseError<'i>> {
+                serialize_nested_tokens(input, output, rewrite, depth + 1);
+                Ok(())
+            });
+            closing_token.to_css(output).unwrap();
+            previous_token = closing_token.serialization_type();
+        }
+    }
+}
+
+fn closing_token(token: &Token) -> Option<Token<'static>> {
+    match token {
+        Token::Function(_) | Token::ParenthesisBlock => Some(Token::CloseParenthesis),
+        Token::SquareBracketBlock => Some(Token::CloseSquareBracket),
+        Token::CurlyBracketBlock => Some(Token::CloseCurlyBracket),
+        _ => None,
+    }
+}
+
+/// Removes the comments from `css`, unless `allow_comments` is set, along with any `<!--` and `-->`
+/// that were used to hide a stylesheet from browsers that didn't know `<style>`. Everything else
+/// is copied as it was written.
+pub fn strip_css_comments(css: &str, allow_comments: bool) -> String {
+    let mut parser_input = ParserInput::new(css);
+    let mut parser = Parser::new(&mut parser_input);
+    let mut stripped = String::new();
+    strip_nested_css_comments(&mut parser, allow_comments, &mut stripped);
+    stripped
+}
+
+fn strip_nested_css_comments(
+    input: &mut Parser<'_, '_>,
+    allow_comments: bool,
+    output: &mut String,
+) {
+    let mut previous_token = TokenSerializationType::nothing();
+    let mut after_stripped = false;
+    loop {
+        let start = input.position();
+        let token = match input.next_including_whitespace_and_comments() {
+            Ok(Token::Comment(_)) if !allow_comments => None,
+            Ok(Token::CDO) | Ok(Token::CDC) => None,
+            Ok(token) => Some(token.clone()),
+            Err(_) => break,
+        };
+        let token = match token {
+            Some(token) => token,
+            None => {
+                after_stripped = true;
                 continue;
             }
         };
-        rules.push(rule);
+
+        // Tokens that were only kept apart by what was stripped need an empty comment instead.
+        let token_type = token.serialization_type();
+        if after_stripped && previous_token.needs_separator_when_before(token_type) {
+            output.push_str("/**/");
+        }
+        after_stripped = false;
+        previous_token = token_type;
+        output.push_str(input.slice_from(start));
+
+        if let Some(closing_token) = closing_token(&token) {
+            let mut contents_end = input.position();
+            let _ = input.parse_nested_block(|input| -> Result<(), CssParseError> {
+                strip_nested_css_comments(input, allow_comments, output);
+                contents_end = input.position();
+                Ok(())
+            });
+            output.push_str(input.slice(contents_end..input.position()));
+            previous_token = closing_token.serialization_type();
+        }
     }
+}
 
-    rules
+pub fn parse_css_stylesheet(css: &str) -> Vec<CssRule> {
+    let mut parser_input = ParserInput::new(css);
+    let mut parser = Parser::new(&mut parser_input);
+
+    parse_rule_list(RuleListParser::new_for_stylesheet(&mut parser, CssParser))
 }
 
 pub fn parse_css_style_attribute(css: &str) -> Vec<CssDeclaration> {
@@ -201,20 +604,15 @@ impl<'i> DeclarationParser<'i> for CssDeclarationParser {
         name: CowRcStr<'i>,
         input: &mut Parser<'i, 't>,
     ) -> Result<Self::Declaration, ParseError<'i, CssError>> {
-        let mut value = String::new();
-        let mut previous_token = TokenSerializationType::nothing();
-        while let Ok(token) = input.next_including_whitespace_and_comments() {
-            let token_type = token.serialization_type();
-            if previous_token.needs_separator_when_before(token_type) {
-                value.push_str("/**/");
-            }
-            previous_token = token_type;
-            token.to_css(&mut value).unwrap();
-        }
+        // `parse_declarations` moves the start of the span back to the property name.
+        let span = CssSpan::at(input);
+        let (value, important) = parse_css_declaration_value(input);
 
         Ok(vec![CssDeclaration {
             property: name.to_string(),
             value,
+            important,
+            span,
         }])
     }
 }
@@ -230,17 +628,30 @@ pub fn parse_declarations<'i>(
     input: &mut Parser<'i, '_>,
 ) -> Result<Vec<CssDeclaration>, Box<dyn Error>> {
     let mut declarations = Vec::new();
-    let declaration_list_parser = DeclarationListParser::new(input, CssDeclarationParser);
+    let mut declaration_list_parser = DeclarationListParser::new(input, CssDeclarationParser);
 
-    for declaration_list in declaration_list_parser {
-        let declaration_list = match declaration_list {
-            Ok(l) => l,
-            Err(e) => {
+    loop {
+        // Skip to the start of the next declaration so that its span starts at the property name.
+        declaration_list_parser.input.skip_whitespace();
+        while declaration_list_parser
+            .input
+            .try_parse(Parser::expect_semicolon)
+            .is_ok()
+        {
+            declaration_list_parser.input.skip_whitespace();
+        }
+        let start = declaration_list_parser.input.state();
+
+        let declaration_list = match declaration_list_parser.next() {
+            Some(Ok(l)) => l,
+            Some(Err(e)) => {
                 eprintln!("CSS declaration dropped: {:?}", e);
                 continue;
             }
+            None => break,
         };
-        for declaration in declaration_list {
+        for mut declaration in declaration_list {
+            declaration.span = CssSpan::since(&start, declaration_list_parser.input);
             declarations.push(declaration);
         }
     }
@@ -248,15 +659,196 @@ pub fn parse_declarations<'i>(
     Ok(declarations)
 }
 
+/// Parses the rest of `input` as a declaration value, returning whether it ended in
+/// `!important`.
+pub fn parse_css_declaration_value(input: &mut Parser<'_, '_>) -> (CssValue, bool) {
+    let mut components = parse_component_values(input);
+    let important = split_important(&mut components);
+    (CssValue(components), important)
+}
+
+fn parse_component_values(input: &mut Parser<'_, '_>) -> Vec<CssComponentValue> {
+    let mut components = Vec::new();
+    while let Ok(token) = input.next_including_whitespace_and_comments() {
+        let component = match *token {
+            Token::WhiteSpace(whitespace) => CssComponentValue::WhiteSpace(whitespace.to_string()),
+            Token::Comment(comment) => CssComponentValue::Comment(comment.to_string()),
+            ref token if closing_token(token).is_some() => {
+                let opening_token = to_owned_token(token);
+                let contents = input
+                    .parse_nested_block(|input| -> Result<_, CssParseError> {
+                        Ok(parse_component_values(input))
+                    })
+                    .unwrap_or_default();
+                CssComponentValue::Block(opening_token, contents)
+            }
+            ref token => CssComponentValue::Token(to_owned_token(token)),
+        };
+        components.push(component);
+    }
+    components
+}
+
+// Removes a trailing `!important` (and the whitespace before it) from a declaration value.
+fn split_important(components: &mut Vec<CssComponentValue>) -> bool {
+    let last_tokens: Vec<usize> = components
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, component)| {
+            !matches!(
+                component,
+                CssComponentValue::WhiteSpace(_) | CssComponentValue::Comment(_)
+            )
+        })
+        .map(|(index, _)| index)
+        .take(2)
+        .collect();
+
+    match last_tokens[..] {
+        [important, bang]
+            if matches!(
+                components[important],
+                CssComponentValue::Token(Token::Ident(ref ident))
+                    if ident.eq_ignore_ascii_case("important")
+            ) && components[bang] == CssComponentValue::Token(Token::Delim('!')) =>
+        {
+            components.truncate(bang);
+            while let Some(CssComponentValue::WhiteSpace(_)) = components.last() {
+                components.pop();
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+// Copies the strings a token borrows from the parser's input so that it can outlive it.
+fn to_owned_token(token: &Token) -> Token<'static> {
+    let owned = |string: &CowRcStr| -> CowRcStr<'static> { string.to_string().into() };
+    match *token {
+        Token::Ident(ref ident) => Token::Ident(owned(ident)),
+        Token::AtKeyword(ref name) => Token::AtKeyword(owned(name)),
+        Token::Hash(ref hash) => Token::Hash(owned(hash)),
+        Token::IDHash(ref id) => Token::IDHash(owned(id)),
+        Token::QuotedString(ref string) => Token::QuotedString(owned(string)),
+        Token::UnquotedUrl(ref url) => Token::UnquotedUrl(owned(url)),
+        Token::Delim(delim) => Token::Delim(delim),
+        Token::Number {
+            has_sign,
+            value,
+            int_value,
+        } => Token::Number {
+            has_sign,
+            value,
+            int_value,
+        },
+        Token::Percentage {
+            has_sign,
+            unit_value,
+            int_value,
+        } => Token::Percentage {
+            has_sign,
+            unit_value,
+            int_value,
+        },
+        Token::Dimension {
+            has_sign,
+            value,
+            int_value,
+            ref unit,
+        } => Token::Dimension {
+            has_sign,
+            value,
+            int_value,
+            unit: owned(unit),
+        },
+        // Whitespace and comments are kept as `CssComponentValue`s instead.
+        Token::WhiteSpace(_) => Token::WhiteSpace(" "),
+        Token::Comment(_) => Token::Comment(""),
+        Token::Colon => Token::Colon,
+        Token::Semicolon => Token::Semicolon,
+        Token::Comma => Token::Comma,
+        Token::IncludeMatch => Token::IncludeMatch,
+        Token::DashMatch => Token::DashMatch,
+        Token::PrefixMatch => Token::PrefixMatch,
+        Token::SuffixMatch => Token::SuffixMatch,
+        Token::SubstringMatch => Token::SubstringMatch,
+        Token::CDO => Token::CDO,
+        Token::CDC => Token::CDC,
+        Token::Function(ref name) => Token::Function(owned(name)),
+        Token::ParenthesisBlock => Token::ParenthesisBlock,
+        Token::SquareBracketBlock => Token::SquareBracketBlock,
+        Token::CurlyBracketBlock => Token::CurlyBracketBlock,
+        Token::BadUrl(ref url) => Token::BadUrl(owned(url)),
+        Token::BadString(ref string) => Token::BadString(owned(string)),
+        Token::CloseParenthesis => Token::CloseParenthesis,
+        Token::CloseSquareBracket => Token::CloseSquareBracket,
+        Token::CloseCurlyBracket => Token::CloseCurlyBracket,
+    }
+}
+
+impl CssValue {
+    pub fn parse(css: &str) -> CssValue {
+        let mut parser_input = ParserInput::new(css);
+        let mut parser = Parser::new(&mut parser_input);
+
+        CssValue(parse_component_values(&mut parser))
+    }
+}
+
+impl ToCss for CssValue {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        serialize_component_values(&self.0, dest)
+    }
+}
+
+fn serialize_component_values<W: fmt::Write>(
+    components: &[CssComponentValue],
+    dest: &mut W,
+) -> fmt::Result {
+    let mut previous_token = TokenSerializationType::nothing();
+    for component in components {
+        let token = match component {
+            CssComponentValue::Token(token) | CssComponentValue::Block(token, _) => token.clone(),
+            CssComponentValue::WhiteSpace(whitespace) => Token::WhiteSpace(whitespace),
+            CssComponentValue::Comment(comment) => Token::Comment(comment),
+        };
+
+        let token_type = token.serialization_type();
+        if previous_token.needs_separator_when_before(token_type) {
+            dest.write_str("/**/")?;
+        }
+        previous_token = token_type;
+        token.to_css(dest)?;
+
+        if let CssComponentValue::Block(_, contents) = component {
+            serialize_component_values(contents, dest)?;
+            let closing_token = closing_token(&token).unwrap();
+            closing_token.to_css(dest)?;
+            previous_token = closing_token.serialization_type();
+        }
+    }
+    Ok(())
+}
+
 impl fmt::Display for CssDeclaration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{};", self.property, self.value)
+        // The name was unescaped when it was parsed, so it's escaped again to keep e.g. a `;` in a
+        // custom property name from ending the declaration.
+        serialize_identifier(&self.property, f)?;
+        f.write_str(":")?;
+        self.value.to_css(f)?;
+        if self.important {
+            f.write_str(" !important")?;
+        }
+        f.write_str(";")
     }
 }
 
 impl Into<String> for CssDeclaration {
     fn into(self) -> String {
-        format!("{}:{};", self.property, self.value)
+        self.to_string()
     }
 }
 
@@ -265,21 +857,43 @@ pub fn serialize_css_rules(rules: &[CssRule]) -> String {
     for rule in rules {
         match rule {
             CssRule::StyleRule(style_rule) => {
-                serialized_rules += &style_rule.selectors;
-                serialized_rules += "{ ";
+                serialized_rules += style_rule.selectors.trim_end();
+                serialized_rules += " { ";
                 serialized_rules += &serialize_css_declarations(&style_rule.declarations);
                 serialized_rules += " }";
             }
             CssRule::AtRule(at_rule) => {
                 serialized_rules += "@";
                 serialized_rules += &at_rule.name;
-                serialized_rules += &at_rule.prelude;
-                if let Some(block) = &at_rule.block {
-                    serialized_rules += "{ ";
-                    serialized_rules += &serialize_css_rules(&block);
-                    serialized_rules += " }";
-                } else {
-                    serialized_rules += "; ";
+                let prelude = at_rule.prelude.to_css_string();
+                if !prelude.is_empty() {
+                    serialized_rules += " ";
+                    serialized_rules += &prelude;
+                }
+                match &at_rule.block {
+                    Some(CssAtRuleBlock::Rules(rules)) => {
+                        serialized_rules += " { ";
+                        serialized_rules += &serialize_css_rules(rules);
+                        serialized_rules += " }";
+                    }
+                    Some(CssAtRuleBlock::Declarations(declarations)) => {
+                        serialized_rules += " { ";
+                        serialized_rules += &serialize_css_declarations(declarations);
+                        serialized_rules += " }";
+                    }
+                    Some(CssAtRuleBlock::Keyframes(keyframes)) => {
+                        serialized_rules += " { ";
+                        for keyframe in keyframes {
+                            serialized_rules += &keyframe.selectors;
+                            serialized_rules += " { ";
+                            serialized_rules += &serialize_css_declarations(&keyframe.declarations);
+                            serialized_rules += " }";
+                        }
+                        serialized_rules += " }";
+                    }
+                    None => {
+                        serialized_rules += "; ";
+                    }
                 }
             }
         }
@@ -297,3 +911,503 @@ pub fn serialize_css_declarations(declarations: &[CssDeclaration]) -> String {
     }
     serialized_declarations
 }
+
+/// Serializes the rules of a stylesheet that was parsed from `source`.
+pub fn serialize_css_stylesheet(
+    rules: &[CssRule],
+    source: &str,
+    serialization: CssSerialization,
+) -> String {
+    let mut serialized = String::new();
+    match serialization {
+        CssSerialization::Normalized => serialized = serialize_css_rules(rules),
+        CssSerialization::Preserved => preserve_nodes(
+            &parse_css_stylesheet(source),
+            rules,
+            source,
+            0..source.len(),
+            &preserve_css_rule,
+            &mut serialized,
+        ),
+        CssSerialization::Minified => minify_css_rules(rules, &mut serialized),
+    }
+    serialized
+}
+
+/// Serializes the declarations of a style attribute that was parsed from `source`.
+pub fn serialize_css_style_attribute(
+    declarations: &[CssDeclaration],
+    source: &str,
+    serialization: CssSerialization,
+) -> String {
+    let mut serialized = String::new();
+    match serialization {
+        CssSerialization::Normalized => serialized = serialize_css_declarations(declarations),
+        CssSerialization::Preserved => preserve_nodes(
+            &parse_css_style_attribute(source),
+            declarations,
+            source,
+            0..source.len(),
+            &preserve_css_declaration,
+            &mut serialized,
+        ),
+        CssSerialization::Minified => minify_css_declarations(declarations, &mut serialized),
+    }
+    serialized
+}
+
+// Writes out `nodes`, the sanitized versions of `originals`, which were parsed from `range` of
+// `source`. Nodes are matched with their originals by where they start. Unchanged nodes are
+// copied from the source along with the whitespace and comments before them, changed ones are
+// handed to `preserve_changed`, and the ones that are gone are skipped over.
+fn preserve_nodes<N: Spanned + PartialEq>(
+    originals: &[N],
+    nodes: &[N],
+    source: &str,
+    range: Range<usize>,
+    preserve_changed: &dyn Fn(&N, &N, &str, &mut String),
+    output: &mut String,
+) {
+    let mut gap_start = range.start;
+    let mut originals = originals.iter();
+    for node in nodes {
+        let span = node.span();
+        let original = loop {
+            match originals.next() {
+                Some(original) if original.span().start_index == span.start_index => {
+                    break Some(original)
+                }
+                Some(original) => gap_start = original.span().end_index,
+                None => break None,
+            }
+        };
+
+        match original {
+            Some(original) => {
+                push_gap(
+                    &source[gap_start.min(span.start_index)..span.start_index],
+                    output,
+                );
+                if node == original {
+                    output.push_str(&source[span.start_index..span.end_index]);
+                } else {
+                    preserve_changed(original, node, source, output);
+                }
+                gap_start = span.end_index;
+            }
+            // Not something that was parsed from the source, so there's nowhere to put it but the
+            // end.
+            None => {
+                output.push(' ');
+                preserve_changed(node, node, "", output);
+            }
+        }
+    }
+    if let Some(last) = originals.last() {
+        gap_start = last.span().end_index;
+    }
+    push_gap(&source[gap_start.min(range.end)..range.end], output);
+}
+
+// The text between two nodes is copied as long as it's only whitespace and comments. Anything else
+// there is something the parser dropped, so only the whitespace at the end is kept.
+fn push_gap(gap: &str, output: &mut String) {
+    let mut parser_input = ParserInput::new(gap);
+    let mut parser = Parser::new(&mut parser_input);
+    let mut is_blank = true;
+    loop {
+        let start = parser.position();
+        match parser.next_including_whitespace_and_comments().cloned() {
+            Ok(Token::WhiteSpace(_)) => {}
+            // An unterminated comment would swallow whatever comes after the gap.
+            Ok(Token::Comment(_)) => {
+                let comment = parser.slice_from(start);
+                if comment.len() < 4 || !comment.ends_with("*/") {
+                    is_blank = false;
+                    break;
+                }
+            }
+            Ok(_) => {
+                is_blank = false;
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+
+    if is_blank {
+        output.push_str(gap);
+    } else {
+        output.push_str(&gap[gap.trim_end().len()..]);
+    }
+}
+
+fn preserve_css_rule(original: &CssRule, rule: &CssRule, source: &str, output: &mut String) {
+    match (original, rule, block_ranges(original.span(), source)) {
+        (
+            CssRule::StyleRule(original),
+            CssRule::StyleRule(style_rule),
+            Some((header, contents, closing)),
+        ) => {
+            if style_rule.selectors == original.selectors {
+                output.push_str(&source[header]);
+            } else {
+                output.push_str(style_rule.selectors.trim_end());
+                output.push_str(" {");
+            }
+            preserve_nodes(
+                &original.declarations,
+                &style_rule.declarations,
+                source,
+                contents,
+                &preserve_css_declaration,
+                output,
+            );
+            output.push_str(&source[closing]);
+        }
+        (
+            CssRule::AtRule(original),
+            CssRule::AtRule(at_rule),
+            Some((header, contents, closing)),
+        ) if same_block_kind(&original.block, &at_rule.block) => {
+            if at_rule.name == original.name && at_rule.prelude == original.prelude {
+                output.push_str(&source[header]);
+            } else {
+                output.push('@');
+                output.push_str(&at_rule.name);
+                output.push(' ');
+                output.push_str(&at_rule.prelude.to_css_string());
+                output.push_str(" {");
+            }
+            match (&original.block, &at_rule.block) {
+                (Some(CssAtRuleBlock::Rules(originals)), Some(CssAtRuleBlock::Rules(rules))) => {
+                    preserve_nodes(
+                        originals,
+                        rules,
+                        source,
+                        contents,
+                        &preserve_css_rule,
+                        output,
+                    )
+                }
+                (
+                    Some(CssAtRuleBlock::Declarations(originals)),
+                    Some(CssAtRuleBlock::Declarations(declarations)),
+                ) => preserve_nodes(
+                    originals,
+                    declarations,
+                    source,
+                    contents,
+                    &preserve_css_declaration,
+                    output,
+                ),
+                (
+                    Some(CssAtRuleBlock::Keyframes(originals)),
+                    Some(CssAtRuleBlock::Keyframes(keyframes)),
+                ) => preserve_nodes(
+                    originals,
+                    keyframes,
+                    source,
+                    contents,
+                    &preserve_css_keyframe,
+                    output,
+                ),
+                // Ruled out by `same_block_kind` above.
+                _ => {}
+            }
+            output.push_str(&source[closing]);
+        }
+        _ => output.push_str(&serialize_css_rules(std::slice::from_ref(rule))),
+    }
+}
+
+fn same_block_kind(original: &Option<CssAtRuleBlock>, block: &Option<CssAtRuleBlock>) -> bool {
+    matches!(
+        (original, block),
+        (
+            Some(CssAtRuleBlock::Rules(_)),
+            Some(CssAtRuleBlock::Rules(_))
+        ) | (
+            Some(CssAtRuleBlock::Declarations(_)),
+            Some(CssAtRuleBlock::Declarations(_))
+        ) | (
+            Some(CssAtRuleBlock::Keyframes(_)),
+            Some(CssAtRuleBlock::Keyframes(_))
+        )
+    )
+}
+
+fn preserve_css_keyframe(
+    original: &CssKeyframe,
+    keyframe: &CssKeyframe,
+    source: &str,
+    output: &mut String,
+) {
+    match block_ranges(original.span, source) {
+        Some((header, contents, closing)) => {
+            if keyframe.selectors == original.selectors {
+                output.push_str(&source[header]);
+            } else {
+                output.push_str(&keyframe.selectors);
+                output.push_str(" {");
+            }
+            preserve_nodes(
+                &original.declarations,
+                &keyframe.declarations,
+                source,
+                contents,
+                &preserve_css_declaration,
+                output,
+            );
+            output.push_str(&source[closing]);
+        }
+        None => {
+            output.push_str(&keyframe.selectors);
+            output.push_str(" { ");
+            output.push_str(&serialize_css_declarations(&keyframe.declarations));
+            output.push_str(" }");
+        }
+    }
+}
+
+fn preserve_css_declaration(
+    _original: &CssDeclaration,
+    declaration: &CssDeclaration,
+    _source: &str,
+    output: &mut String,
+) {
+    output.push_str(&declaration.to_string());
+}
+
+// Splits the source of a rule with a block into the part up to and including the `{`, the
+// contents of the block, and the closing `}` (which is missing if the source ended first).
+fn block_ranges(span: CssSpan, source: &str) -> Option<(Range<usize>, Range<usize>, Range<usize>)> {
+    let rule_source = source.get(span.start_index..span.end_index)?;
+    let mut parser_input = ParserInput::new(rule_source);
+    let mut parser = Parser::new(&mut parser_input);
+    while let Ok(token) = parser.next() {
+        if let Token::CurlyBracketBlock = token {
+            let contents_start = span.start_index + parser.position().byte_index();
+            // The contents end where the nested parser stops: at the closing `}`, or at the end of
+            // the source. A trailing `}` alone could belong to a rule nested inside an unclosed
+            // block.
+            let contents_end = span.start_index
+                + parser
+                    .parse_nested_block(|parser| {
+                        while parser.next_including_whitespace_and_comments().is_ok() {}
+                        Ok::<_, ParseError<'_, ()>>(parser.position().byte_index())
+                    })
+                    .ok()?;
+            let contents_end = contents_end.max(contents_start).min(span.end_index);
+            return Some((
+                span.start_index..contents_start,
+                contents_start..contents_end,
+                contents_end..span.end_index,
+            ));
+        }
+    }
+    None
+}
+
+fn minify_css_rules(rules: &[CssRule], output: &mut String) {
+    for rule in rules {
+        match rule {
+            CssRule::StyleRule(style_rule) => {
+                output.push_str(&minify_css(&style_rule.selectors, &SELECTOR_SEPARATORS));
+                output.push('{');
+                minify_css_declarations(&style_rule.declarations, output);
+                output.push('}');
+            }
+            CssRule::AtRule(at_rule) => {
+                output.push('@');
+                output.push_str(&at_rule.name);
+                let prelude = minify_css(&at_rule.prelude.to_css_string(), &VALUE_SEPARATORS);
+                if !prelude.is_empty() {
+                    output.push(' ');
+                    output.push_str(&prelude);
+                }
+                match &at_rule.block {
+                    Some(CssAtRuleBlock::Rules(rules)) => {
+                        output.push('{');
+                        minify_css_rules(rules, output);
+                        output.push('}');
+                    }
+                    Some(CssAtRuleBlock::Declarations(declarations)) => {
+                        output.push('{');
+                        minify_css_declarations(declarations, output);
+                        output.push('}');
+                    }
+                    Some(CssAtRuleBlock::Keyframes(keyframes)) => {
+                        output.push('{');
+                        for keyframe in keyframes {
+                            output.push_str(&minify_css(&keyframe.selectors, &VALUE_SEPARATORS));
+                            output.push('{');
+                            minify_css_declarations(&keyframe.declarations, output);
+                            output.push('}');
+                        }
+                        output.push('}');
+                    }
+                    None => output.push(';'),
+                }
+            }
+        }
+    }
+}
+
+fn minify_css_declarations(declarations: &[CssDeclaration], output: &mut String) {
+    for (index, declaration) in declarations.iter().enumerate() {
+        if index != 0 {
+            output.push(';');
+        }
+        serialize_identifier(&declaration.property, output).unwrap();
+        output.push(':');
+        let value = minify_component_values(&declaration.value.0, &VALUE_SEPARATORS);
+        serialize_component_values(&value, output).unwrap();
+        if declaration.important {
+            output.push_str("!important");
+        }
+    }
+}
+
+// Whitespace is only dropped next to tokens where it can't be significant. That rules out `+` and
+// `-` in values, since `calc()` needs the whitespace around them, and `:` in selectors.
+const VALUE_SEPARATORS: [Token<'static>; 2] = [Token::Comma, Token::Colon];
+const SELECTOR_SEPARATORS: [Token<'static>; 4] = [
+    Token::Comma,
+    Token::Delim('>'),
+    Token::Delim('+'),
+    Token::Delim('~'),
+];
+
+fn minify_css(css: &str, separators: &[Token]) -> String {
+    let components = minify_component_values(&CssValue::parse(css).0, separators);
+    let mut minified = String::new();
+    serialize_component_values(&components, &mut minified).unwrap();
+    minified
+}
+
+// Drops comments and collapses whitespace, leaving none at all at the ends of a block or next to
+// one of `separators`. `serialize_component_values` puts back an empty comment wherever two tokens
+// would otherwise run together.
+fn minify_component_values(
+    components: &[CssComponentValue],
+    separators: &[Token],
+) -> Vec<CssComponentValue> {
+    let mut minified: Vec<CssComponentValue> = Vec::new();
+    let mut after_separator = true;
+    for component in components {
+        match component {
+            CssComponentValue::Comment(_) => {}
+            CssComponentValue::WhiteSpace(_) => {
+                if !after_separator
+                    && !matches!(minified.last(), Some(CssComponentValue::WhiteSpace(_)))
+                {
+                    minified.push(CssComponentValue::WhiteSpace(" ".to_string()));
+                }
+            }
+            CssComponentValue::Token(token) => {
+                let is_separator = separators.contains(token);
+                if is_separator {
+                    if let Some(CssComponentValue::WhiteSpace(_)) = minified.last() {
+                        minified.pop();
+                    }
+                }
+                minified.push(component.clone());
+                after_separator = is_separator;
+            }
+            CssComponentValue::Block(token, contents) => {
+                minified.push(CssComponentValue::Block(
+                    token.clone(),
+                    minify_component_values(contents, separators),
+                ));
+                after_separator = false;
+            }
+        }
+    }
+    if let Some(CssComponentValue::WhiteSpace(_)) = minified.last() {
+        minified.pop();
+    }
+    minified
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn location(line: u32, column: u32) -> SourceLocation {
+        SourceLocation { line, column }
+    }
+
+    #[test]
+    fn parse_typed_rules_with_spans() {
+        let rules = parse_css_stylesheet(
+            "@import url(a.css) screen;\n\
+             @media print {\n  p { color: red !important; margin: calc(1px + 2px) }\n}\n\
+             @keyframes \"fade\" { to { opacity: 0 } }",
+        );
+        assert_eq!(rules.len(), 3);
+
+        match rules[0] {
+            CssRule::AtRule(CssAtRule {
+                prelude:
+                    CssAtRulePrelude::Import {
+                        ref url,
+                        ref conditions,
+                    },
+                block: None,
+                span,
+                ..
+            }) => {
+                assert_eq!(url, "a.css");
+                assert_eq!(conditions, "screen");
+                assert_eq!((span.start, span.end), (location(0, 1), location(0, 27)));
+            }
+            ref rule => panic!("unexpected rule: {:?}", rule),
+        }
+
+        match rules[1] {
+            CssRule::AtRule(CssAtRule {
+                prelude: CssAtRulePrelude::Media(ref queries),
+                block: Some(CssAtRuleBlock::Rules(ref rules)),
+                span,
+                ..
+            }) => {
+                assert_eq!(queries.len(), 1);
+                assert_eq!(queries[0].media_type.as_deref(), Some("print"));
+                assert_eq!((span.start, span.end), (location(1, 1), location(3, 2)));
+                let declarations = match rules[0] {
+                    CssRule::StyleRule(ref style_rule) => &style_rule.declarations,
+                    ref rule => panic!("unexpected rule: {:?}", rule),
+                };
+                assert!(declarations[0].important);
+                assert_eq!(declarations[0].to_string(), "color: red !important;");
+                assert_eq!(
+                    (declarations[0].span.start, declarations[0].span.end),
+                    (location(2, 7), location(2, 29))
+                );
+                assert!(!declarations[1].important);
+                assert_eq!(
+                    declarations[1].value.0[1],
+                    CssComponentValue::Block(
+                        Token::Function("calc".into()),
+                        CssValue::parse("1px + 2px").0
+                    )
+                );
+            }
+            ref rule => panic!("unexpected rule: {:?}", rule),
+        }
+
+        match rules[2] {
+            CssRule::AtRule(CssAtRule {
+                prelude: CssAtRulePrelude::Keyframes { ref name, quoted },
+                block: Some(CssAtRuleBlock::Keyframes(ref keyframes)),
+                ..
+            }) => {
+                assert_eq!(name, "fade");
+                assert!(quoted);
+                assert_eq!(keyframes[0].span.start, location(4, 21));
+            }
+            ref rule => panic!("unexpected rule: {:?}", rule),
+        }
+    }
+}
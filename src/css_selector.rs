@@ -0,0 +1,413 @@
+use cssparser::{CowRcStr, Delimiter, Parser, ParserInput, SourceLocation, ToCss};
+use html5ever::{LocalName, Namespace, Prefix};
+use selectors::attr::{AttrSelectorOperation, CaseSensitivity, NamespaceConstraint};
+use selectors::context::{MatchingContext, MatchingMode, QuirksMode};
+use selectors::matching::{matches_selector, ElementSelectorFlags};
+use selectors::parser::{
+    Component, NonTSPseudoClass, Parser as SelectorParser, Selector, SelectorImpl, SelectorList,
+    SelectorParseErrorKind,
+};
+use selectors::{Element, OpaqueElement};
+use std::fmt;
+
+use crate::arena_dom::{Attribute, NodeData, Ref};
+use crate::css_parser::serialize_css_declarations;
+
+pub type CssSelector = Selector<CssSelectorImpl>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CssSelectorImpl;
+
+impl SelectorImpl for CssSelectorImpl {
+    type ExtraMatchingData = ();
+    type AttrValue = String;
+    type Identifier = LocalName;
+    type ClassName = LocalName;
+    type PartName = LocalName;
+    type LocalName = LocalName;
+    type NamespaceUrl = Namespace;
+    type NamespacePrefix = Prefix;
+    type BorrowedNamespaceUrl = Namespace;
+    type BorrowedLocalName = LocalName;
+    type NonTSPseudoClass = PseudoClass;
+    type PseudoElement = PseudoElement;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PseudoClass {
+    Active,
+    AnyLink,
+    Checked,
+    Disabled,
+    Enabled,
+    Focus,
+    Hover,
+    Indeterminate,
+    Link,
+    Visited,
+}
+
+impl NonTSPseudoClass for PseudoClass {
+    type Impl = CssSelectorImpl;
+
+    fn is_active_or_hover(&self) -> bool {
+        matches!(*self, PseudoClass::Active | PseudoClass::Hover)
+    }
+
+    fn is_user_action_state(&self) -> bool {
+        matches!(
+            *self,
+            PseudoClass::Active | PseudoClass::Hover | PseudoClass::Focus
+        )
+    }
+
+    fn has_zero_specificity(&self) -> bool {
+        false
+    }
+}
+
+impl ToCss for PseudoClass {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        dest.write_str(match *self {
+            PseudoClass::Active => ":active",
+            PseudoClass::AnyLink => ":any-link",
+            PseudoClass::Checked => ":checked",
+            PseudoClass::Disabled => ":disabled",
+            PseudoClass::Enabled => ":enabled",
+            PseudoClass::Focus => ":focus",
+            PseudoClass::Hover => ":hover",
+            PseudoClass::Indeterminate => ":indeterminate",
+            PseudoClass::Link => ":link",
+            PseudoClass::Visited => ":visited",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PseudoElement {
+    After,
+    Before,
+    FirstLetter,
+    FirstLine,
+    Marker,
+    Placeholder,
+    Selection,
+}
+
+impl selectors::parser::PseudoElement for PseudoElement {
+    type Impl = CssSelectorImpl;
+}
+
+impl ToCss for PseudoElement {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        dest.write_str(match *self {
+            PseudoElement::After => "::after",
+            PseudoElement::Before => "::before",
+            PseudoElement::FirstLetter => "::first-letter",
+            PseudoElement::FirstLine => "::first-line",
+            PseudoElement::Marker => "::marker",
+            PseudoElement::Placeholder => "::placeholder",
+            PseudoElement::Selection => "::selection",
+        })
+    }
+}
+
+struct CssSelectorParser;
+
+impl<'i> SelectorParser<'i> for CssSelectorParser {
+    type Impl = CssSelectorImpl;
+    type Error = SelectorParseErrorKind<'i>;
+
+    fn parse_non_ts_pseudo_class(
+        &self,
+        location: SourceLocation,
+        name: CowRcStr<'i>,
+    ) -> Result<PseudoClass, cssparser::ParseError<'i, Self::Error>> {
+        match_ignore_ascii_case! { &name,
+            "active" => Ok(PseudoClass::Active),
+            "any-link" => Ok(PseudoClass::AnyLink),
+            "checked" => Ok(PseudoClass::Checked),
+            "disabled" => Ok(PseudoClass::Disabled),
+            "enabled" => Ok(PseudoClass::Enabled),
+            "focus" => Ok(PseudoClass::Focus),
+            "hover" => Ok(PseudoClass::Hover),
+            "indeterminate" => Ok(PseudoClass::Indeterminate),
+            "link" => Ok(PseudoClass::Link),
+            "visited" => Ok(PseudoClass::Visited),
+            _ => Err(location.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+
+    fn parse_pseudo_element(
+        &self,
+        location: SourceLocation,
+        name: CowRcStr<'i>,
+    ) -> Result<PseudoElement, cssparser::ParseError<'i, Self::Error>> {
+        match_ignore_ascii_case! { &name,
+            "after" => Ok(PseudoElement::After),
+            "before" => Ok(PseudoElement::Before),
+            "first-letter" => Ok(PseudoElement::FirstLetter),
+            "first-line" => Ok(PseudoElement::FirstLine),
+            "marker" => Ok(PseudoElement::Marker),
+            "placeholder" => Ok(PseudoElement::Placeholder),
+            "selection" => Ok(PseudoElement::Selection),
+            _ => Err(location.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+}
+
+/// Parses a comma-separated selector list, one selector at a time so that a selector the parser
+/// doesn't support (e.g. one using `:has()`) is dropped without taking the rest of the list with
+/// it.
+pub fn parse_css_selectors(selectors: &str) -> Vec<CssSelector> {
+    let mut parser_input = ParserInput::new(selectors);
+    let mut parser = Parser::new(&mut parser_input);
+
+    let mut parsed_selectors = Vec::new();
+    loop {
+        let result = parser.parse_until_before(Delimiter::Comma, |input| {
+            SelectorList::parse(&CssSelectorParser, input)
+        });
+        // Selectors that don't parse are dropped.
+        if let Ok(list) = result {
+            parsed_selectors.extend(list.0);
+        }
+        if parser.next().is_err() {
+            break;
+        }
+    }
+    parsed_selectors
+}
+
+/// Parses a comma-separated selector list like `parse_css_selectors`, but returns `None` if any of
+/// the selectors can't be parsed.
+pub fn parse_css_selector_list(selectors: &str) -> Option<Vec<CssSelector>> {
+    let mut parser_input = ParserInput::new(selectors);
+    let mut parser = Parser::new(&mut parser_input);
+    SelectorList::parse(&CssSelectorParser, &mut parser)
+        .ok()
+        .map(|list| list.0.into_vec())
+}
+
+/// Returns every simple selector and combinator in `selector`, including those nested inside
+/// `:not()`.
+pub fn selector_components(selector: &CssSelector) -> Vec<&Component<CssSelectorImpl>> {
+    let mut components = Vec::new();
+    let mut stack: Vec<&Component<CssSelectorImpl>> = selector.iter_raw_match_order().collect();
+    while let Some(component) = stack.pop() {
+        if let Component::Negation(ref negated) = *component {
+            stack.extend(negated.iter());
+        }
+        components.push(component);
+    }
+    components
+}
+
+/// Whether `selector` matches `element`. Pseudo-classes that depend on user interaction or browser
+/// state, like `:hover` or `:visited`, never match, and neither do pseudo-elements.
+pub fn matches_css_selector(selector: &CssSelector, element: Ref) -> bool {
+    // Selectors only ever match elements, but `*` and `:not()` would take anything.
+    if !matches!(element.data, NodeData::Element { .. }) {
+        return false;
+    }
+    let mut context = MatchingContext::new(MatchingMode::Normal, None, None, QuirksMode::NoQuirks);
+    matches_selector(selector, 0, None, &element, &mut context, &mut |_, _| {})
+}
+
+/// Whether `selector` only tests things that are in the document, so that the elements it matches
+/// can be found without a browser.
+pub fn is_static_css_selector(selector: &CssSelector) -> bool {
+    selector_components(selector).into_iter().all(|component| {
+        !matches!(
+            *component,
+            Component::NonTSPseudoClass(_) | Component::PseudoElement(_)
+        )
+    })
+}
+
+fn element_sibling<'arena>(
+    node: Ref<'arena>,
+    next: impl Fn(Ref<'arena>) -> Option<Ref<'arena>>,
+) -> Option<Ref<'arena>> {
+    let mut sibling = next(node);
+    while let Some(node) = sibling {
+        if let NodeData::Element { .. } = node.data {
+            return Some(node);
+        }
+        sibling = next(node);
+    }
+    None
+}
+
+// Calls `f` with the value of each attribute named `local_name` on `element`, until it returns
+// true.
+fn any_attribute_value(element: Ref, local_name: &LocalName, f: impl Fn(&str) -> bool) -> bool {
+    if let NodeData::Element { ref attrs, .. } = element.data {
+        attrs.borrow().iter().any(|attr| match attr {
+            Attribute::Text(attr) => attr.name.local == *local_name && f(&attr.value),
+            Attribute::Style(attr) => {
+                attr.name.local == *local_name && f(&serialize_css_declarations(&attr.value))
+            }
+        })
+    } else {
+        false
+    }
+}
+
+impl<'arena> Element for Ref<'arena> {
+    type Impl = CssSelectorImpl;
+
+    fn opaque(&self) -> OpaqueElement {
+        OpaqueElement::new(*self)
+    }
+
+    fn parent_element(&self) -> Option<Self> {
+        self.parent
+            .get()
+            .filter(|parent| matches!(parent.data, NodeData::Element { .. }))
+    }
+
+    fn parent_node_is_shadow_root(&self) -> bool {
+        false
+    }
+
+    fn containing_shadow_host(&self) -> Option<Self> {
+        None
+    }
+
+    fn is_pseudo_element(&self) -> bool {
+        false
+    }
+
+    fn prev_sibling_element(&self) -> Option<Self> {
+        element_sibling(self, |node| node.previous_sibling.get())
+    }
+
+    fn next_sibling_element(&self) -> Option<Self> {
+        element_sibling(self, |node| node.next_sibling.get())
+    }
+
+    fn is_html_element_in_html_document(&self) -> bool {
+        matches!(self.data, NodeData::Element { ref name, .. } if name.ns == ns!(html))
+    }
+
+    fn has_local_name(&self, local_name: &LocalName) -> bool {
+        matches!(self.data, NodeData::Element { ref name, .. } if name.local == *local_name)
+    }
+
+    fn has_namespace(&self, ns: &Namespace) -> bool {
+        matches!(self.data, NodeData::Element { ref name, .. } if name.ns == *ns)
+    }
+
+    fn is_same_type(&self, other: &Self) -> bool {
+        match (&self.data, &other.data) {
+            (
+                NodeData::Element { name, .. },
+                NodeData::Element {
+                    name: other_name, ..
+                },
+            ) => name == other_name,
+            _ => false,
+        }
+    }
+
+    fn attr_matches(
+        &self,
+        ns: &NamespaceConstraint<&Namespace>,
+        local_name: &LocalName,
+        operation: &AttrSelectorOperation<&String>,
+    ) -> bool {
+        // Only attributes without a namespace are ever selected, since the parser doesn't support
+        // namespace prefixes in selectors.
+        match *ns {
+            NamespaceConstraint::Specific(ns) if *ns != ns!() => false,
+            _ => any_attribute_value(self, local_name, |value| operation.eval_str(value)),
+        }
+    }
+
+    fn match_non_ts_pseudo_class<F>(
+        &self,
+        _pc: &PseudoClass,
+        _context: &mut MatchingContext<CssSelectorImpl>,
+        _flags_setter: &mut F,
+    ) -> bool
+    where
+        F: FnMut(&Self, ElementSelectorFlags),
+    {
+        false
+    }
+
+    fn match_pseudo_element(
+        &self,
+        _pe: &PseudoElement,
+        _context: &mut MatchingContext<CssSelectorImpl>,
+    ) -> bool {
+        false
+    }
+
+    fn is_link(&self) -> bool {
+        matches!(
+            self.data,
+            NodeData::Element { ref name, .. } if name.ns == ns!(html)
+                && matches!(
+                    name.local,
+                    local_name!("a") | local_name!("area") | local_name!("link")
+                )
+        ) && any_attribute_value(self, &local_name!("href"), |_| true)
+    }
+
+    fn is_html_slot_element(&self) -> bool {
+        false
+    }
+
+    fn has_id(&self, id: &LocalName, case_sensitivity: CaseSensitivity) -> bool {
+        any_attribute_value(self, &local_name!("id"), |value| {
+            case_sensitivity.eq(value.as_bytes(), id.as_bytes())
+        })
+    }
+
+    fn has_class(&self, name: &LocalName, case_sensitivity: CaseSensitivity) -> bool {
+        any_attribute_value(self, &local_name!("class"), |value| {
+            value
+                .split_ascii_whitespace()
+                .any(|class| case_sensitivity.eq(class.as_bytes(), name.as_bytes()))
+        })
+    }
+
+    fn exported_part(&self, _name: &LocalName) -> Option<LocalName> {
+        None
+    }
+
+    fn imported_part(&self, _name: &LocalName) -> Option<LocalName> {
+        None
+    }
+
+    fn is_part(&self, _name: &LocalName) -> bool {
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        let mut child = self.first_child.get();
+        while let Some(node) = child {
+            match node.data {
+                NodeData::Element { .. } => return false,
+                NodeData::Text { ref contents } if !contents.borrow().is_empty() => return false,
+                _ => {}
+            }
+            child = node.next_sibling.get();
+        }
+        true
+    }
+
+    fn is_root(&self) -> bool {
+        matches!(self.data, NodeData::Element { .. })
+            && matches!(
+                self.parent.get().map(|parent| &parent.data),
+                Some(NodeData::Document)
+            )
+    }
+}
@@ -0,0 +1,603 @@
+use cssparser::{parse_color_keyword, CowRcStr, ParseError, Parser, ParserInput, Token};
+use std::collections::{HashMap, HashSet};
+
+use crate::css_parser::{serialize_tokens, CssComponentValue, CssValue};
+
+/// A kind of component that may appear in a CSS declaration value.
+///
+/// A property with an entry in `allowed_css_values` only accepts values made up of these
+/// components (plus whitespace, commas, `/` separators, the CSS-wide keywords and `!important`).
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum CssValueType {
+    Keyword(&'static str),
+    Length,
+    Percentage,
+    Number,
+    Integer,
+    Angle,
+    Time,
+    Color,
+    String,
+    Url,
+    Function(&'static str),
+}
+
+/// An edit made to the value of a CSS declaration instead of dropping the declaration.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CssValueRewrite {
+    /// Caps dimensions in `unit` (or percentages, with a unit of `"%"`) at `max`, and negative ones
+    /// at `-max`.
+    ClampDimension { unit: &'static str, max: f32 },
+    /// Clamps integers, e.g. a `z-index`, to `min..=max`.
+    ClampInteger { min: i32, max: i32 },
+    /// Converts dimensions in unit `from` to unit `to`, multiplying them by `factor`.
+    ConvertUnit {
+        from: &'static str,
+        to: &'static str,
+        factor: f32,
+    },
+    /// Replaces the keyword `from` with `to`, e.g. `position: fixed` with `position: static`.
+    ReplaceKeyword {
+        from: &'static str,
+        to: &'static str,
+    },
+    /// Removes `!important`.
+    StripImportant,
+    /// Caps times, in `s` or `ms`, at `max` seconds, e.g. to limit how long an animation runs.
+    ClampTime { max: f32 },
+    /// Caps the iteration counts in `animation` or `animation-iteration-count` at `max`,
+    /// including `infinite`.
+    ClampIterationCount { max: f32 },
+}
+
+/// What to do with `var()` references to custom properties in the values of other declarations.
+///
+/// Since a custom property can hold any tokens, a `var()` would otherwise let a value get around
+/// the property's `allowed_css_values`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CssVarPolicy {
+    /// Declarations that use `var()` are removed.
+    Remove,
+    /// `var()` may refer to the allowed custom properties. Properties with `allowed_css_values`
+    /// still only accept it if `CssValueType::Function("var")` is one of them, and properties with
+    /// value rewrites other than `StripImportant` don't accept it at all.
+    Allow,
+    /// `var()` references are replaced by the value of the custom property declared in the same
+    /// stylesheet or style attribute, or their fallback, and the result is then checked like any
+    /// other value. Declarations with references that can't be resolved are removed.
+    Resolve,
+}
+
+// Functions that are never allowed, whatever the property. `expression()` runs script in old IE.
+const DANGEROUS_FUNCTIONS: &[&str] = &["expression"];
+
+// Functions whose string arguments are fetched as URLs, just like `url()`.
+const URL_FUNCTIONS: &[&str] = &[
+    "cross-fade",
+    "image",
+    "image-set",
+    "-webkit-cross-fade",
+    "-webkit-image-set",
+];
+
+const COLOR_FUNCTIONS: &[&str] = &["hsl", "hsla", "rgb", "rgba"];
+
+const CSS_WIDE_KEYWORDS: &[&str] = &["inherit", "initial", "revert", "unset"];
+
+const LENGTH_UNITS: &[&str] = &[
+    "ch", "cm", "em", "ex", "in", "mm", "pc", "pt", "px", "q", "rem", "vh", "vmax", "vmin", "vw",
+];
+
+const ANGLE_UNITS: &[&str] = &["deg", "grad", "rad", "turn"];
+
+const TIME_UNITS: &[&str] = &["ms", "s"];
+
+// Functions that compute a value, so there's no telling what a clamp would be up against.
+const MATH_FUNCTIONS: &[&str] = &[
+    "abs",
+    "calc",
+    "clamp",
+    "exp",
+    "hypot",
+    "log",
+    "max",
+    "min",
+    "mod",
+    "pow",
+    "rem",
+    "round",
+    "sign",
+    "sqrt",
+    "-moz-calc",
+    "-webkit-calc",
+];
+
+/// Checks a declaration value against the allowed value types for its property.
+///
+/// When `allowed_types` is `None` the property has no grammar and any value is accepted, but
+/// dangerous functions are still rejected and every URL must pass `is_allowed_url`.
+pub fn is_allowed_css_value(
+    value: &str,
+    allowed_types: Option<&HashSet<CssValueType>>,
+    is_allowed_url: &dyn Fn(&str) -> bool,
+) -> bool {
+    let mut parser_input = ParserInput::new(value);
+    let mut parser = Parser::new(&mut parser_input);
+    let validator = CssValueValidator {
+        allowed_types,
+        is_allowed_url,
+    };
+    validator.is_allowed(&mut parser, None)
+}
+
+/// Returns the names of the custom properties referenced by `var()`s in `value`.
+pub fn css_var_references(value: &CssValue) -> Vec<&str> {
+    let mut names = Vec::new();
+    collect_css_var_references(&value.0, &mut names);
+    names
+}
+
+fn collect_css_var_references<'a>(components: &'a [CssComponentValue], names: &mut Vec<&'a str>) {
+    for component in components {
+        if let CssComponentValue::Block(token, contents) = component {
+            if let Some((name, _)) = parse_var_function(token, contents) {
+                names.push(name);
+            }
+            collect_css_var_references(contents, names);
+        }
+    }
+}
+
+/// Replaces the `var()`s in `value` with the values of the custom properties they reference, or
+/// with their fallbacks. Returns `None` if any of them can't be resolved, or if the result would
+/// be too big.
+pub fn resolve_css_var_references(
+    value: &CssValue,
+    custom_properties: &HashMap<String, CssValue>,
+) -> Option<CssValue> {
+    let mut budget = MAX_VAR_COMPONENTS;
+    resolve_var_references(&value.0, custom_properties, 0, &mut budget).map(CssValue)
+}
+
+// Custom properties can reference each other, so `depth` guards against cycles. Each one can also
+// reference the next many times over, so `budget` limits how many component values are copied in
+// all.
+fn resolve_var_references(
+    components: &[CssComponentValue],
+    custom_properties: &HashMap<String, CssValue>,
+    depth: usize,
+    budget: &mut usize,
+) -> Option<Vec<CssComponentValue>> {
+    if depth > MAX_VAR_DEPTH {
+        return None;
+    }
+
+    let mut resolved = Vec::new();
+    for component in components {
+        *budget = budget.checked_sub(1)?;
+        match component {
+            CssComponentValue::Block(token, contents) => {
+                match parse_var_function(token, contents) {
+                    Some((name, fallback)) => {
+                        let substitute = match (custom_properties.get(name), fallback) {
+                            (Some(value), _) => &value.0[..],
+                            (None, Some(fallback)) => fallback,
+                            (None, None) => return None,
+                        };
+                        resolved.extend(resolve_var_references(
+                            trim_whitespace(substitute),
+                            custom_properties,
+                            depth + 1,
+                            budget,
+                        )?);
+                    }
+                    None => resolved.push(CssComponentValue::Block(
+                        token.clone(),
+                        resolve_var_references(contents, custom_properties, depth, budget)?,
+                    )),
+                }
+            }
+            component => resolved.push(component.clone()),
+        }
+    }
+    Some(resolved)
+}
+
+const MAX_VAR_DEPTH: usize = 8;
+
+const MAX_VAR_COMPONENTS: usize = 1024;
+
+// Splits `var(--name, fallback)` into the name and the fallback, if it has one.
+fn parse_var_function<'a>(
+    token: &Token,
+    contents: &'a [CssComponentValue],
+) -> Option<(&'a str, Option<&'a [CssComponentValue]>)> {
+    match token {
+        Token::Function(name) if name.eq_ignore_ascii_case("var") => {}
+        _ => return None,
+    }
+    let contents = trim_whitespace(contents);
+    let name = match contents.first() {
+        Some(CssComponentValue::Token(Token::Ident(name))) if name.starts_with("--") => name,
+        _ => return None,
+    };
+    let rest = trim_whitespace(&contents[1..]);
+    match rest.first() {
+        None => Some((name, None)),
+        Some(CssComponentValue::Token(Token::Comma)) => Some((name, Some(&rest[1..]))),
+        Some(_) => None,
+    }
+}
+
+fn trim_whitespace(components: &[CssComponentValue]) -> &[CssComponentValue] {
+    let is_whitespace = |component: &CssComponentValue| {
+        matches!(
+            component,
+            CssComponentValue::WhiteSpace(_) | CssComponentValue::Comment(_)
+        )
+    };
+    let start = components
+        .iter()
+        .position(|component| !is_whitespace(component))
+        .unwrap_or(components.len());
+    let end = components
+        .iter()
+        .rposition(|component| !is_whitespace(component))
+        .map_or(start, |end| end + 1);
+    &components[start..end]
+}
+
+/// Applies `rewrites`, in order, to every component of a declaration value.
+///
+/// Returns `None` if one of them caps values and the value uses a math function like `calc()`,
+/// since whatever that computes can't be capped.
+pub fn rewrite_css_value(value: &str, rewrites: &[&CssValueRewrite]) -> Option<String> {
+    if rewrites.iter().any(|rewrite| rewrite.is_clamp()) {
+        let mut parser_input = ParserInput::new(value);
+        if has_math_function(&mut Parser::new(&mut parser_input)) {
+            return None;
+        }
+    }
+
+    let strip_important = rewrites.contains(&&CssValueRewrite::StripImportant);
+    let mut stripping_important = false;
+
+    let mut parser_input = ParserInput::new(value);
+    let mut parser = Parser::new(&mut parser_input);
+    let mut rewritten = String::new();
+    serialize_tokens(&mut parser, &mut rewritten, &mut |token, depth| {
+        if strip_important {
+            match token {
+                Token::Delim('!') => {
+                    stripping_important = true;
+                    return None;
+                }
+                Token::WhiteSpace(_) | Token::Comment(_) if stripping_important => return None,
+                Token::Ident(ref ident)
+                    if stripping_important && ident.eq_ignore_ascii_case("important") =>
+                {
+                    stripping_important = false;
+                    return None;
+                }
+                _ => stripping_important = false,
+            }
+        }
+        Some(
+            rewrites
+                .iter()
+                .fold(token, |token, rewrite| rewrite_token(rewrite, token, depth)),
+        )
+    });
+
+    if strip_important {
+        rewritten.truncate(rewritten.trim_end().len());
+    }
+    Some(rewritten)
+}
+
+impl CssValueRewrite {
+    fn is_clamp(&self) -> bool {
+        matches!(
+            self,
+            CssValueRewrite::ClampDimension { .. }
+                | CssValueRewrite::ClampInteger { .. }
+                | CssValueRewrite::ClampTime { .. }
+                | CssValueRewrite::ClampIterationCount { .. }
+        )
+    }
+}
+
+fn has_math_function(input: &mut Parser) -> bool {
+    while let Ok(token) = input.next() {
+        match token.clone() {
+            Token::Function(ref name)
+                if MATH_FUNCTIONS
+                    .iter()
+                    .any(|function| name.eq_ignore_ascii_case(function)) =>
+            {
+                return true
+            }
+            Token::Function(_)
+            | Token::ParenthesisBlock
+            | Token::SquareBracketBlock
+            | Token::CurlyBracketBlock => {
+                let nested = input.parse_nested_block(|input| {
+                    Ok::<_, ParseError<'_, ()>>(has_math_function(input))
+                });
+                if nested.unwrap_or(false) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+// `depth` is how many blocks deep the token is, so that e.g. the numbers in `steps(4)` aren't
+// mistaken for iteration counts.
+fn rewrite_token<'i>(rewrite: &CssValueRewrite, token: Token<'i>, depth: usize) -> Token<'i> {
+    match (rewrite, token) {
+        (
+            CssValueRewrite::ClampDimension { unit, max },
+            Token::Dimension {
+                value,
+                unit: token_unit,
+                has_sign,
+                int_value,
+            },
+        ) => {
+            if token_unit.eq_ignore_ascii_case(unit) && value.abs() > *max {
+                dimension(max.copysign(value), token_unit)
+            } else {
+                Token::Dimension {
+                    value,
+                    unit: token_unit,
+                    has_sign,
+                    int_value,
+                }
+            }
+        }
+        (
+            CssValueRewrite::ClampDimension { unit: "%", max },
+            Token::Percentage { unit_value, .. },
+        ) if (unit_value * 100.).abs() > *max => {
+            dimension(max.copysign(unit_value), CowRcStr::from("%"))
+        }
+        (
+            CssValueRewrite::ClampInteger { min, max },
+            Token::Number {
+                int_value: Some(integer),
+                has_sign,
+                ..
+            },
+        ) if integer < *min || integer > *max => {
+            let clamped = integer.max(*min).min(*max);
+            Token::Number {
+                value: clamped as f32,
+                // Keeps an explicit `+`.
+                has_sign: has_sign && integer >= 0 && clamped >= 0,
+                int_value: Some(clamped),
+            }
+        }
+        (
+            CssValueRewrite::ConvertUnit { from, to, factor },
+            Token::Dimension {
+                value, ref unit, ..
+            },
+        ) if unit.eq_ignore_ascii_case(from) => dimension(value * factor, CowRcStr::from(*to)),
+        (CssValueRewrite::ReplaceKeyword { from, to }, Token::Ident(ref ident))
+            if ident.eq_ignore_ascii_case(from) =>
+        {
+            Token::Ident(CowRcStr::from(*to))
+        }
+        (
+            CssValueRewrite::ClampTime { max },
+            Token::Dimension {
+                value, ref unit, ..
+            },
+        ) if (unit.eq_ignore_ascii_case("s") && value > *max)
+            || (unit.eq_ignore_ascii_case("ms") && value > max * 1000.) =>
+        {
+            dimension(*max, CowRcStr::from("s"))
+        }
+        (CssValueRewrite::ClampIterationCount { max }, Token::Number { value, .. })
+            if depth == 0 && value > *max =>
+        {
+            number(*max)
+        }
+        (CssValueRewrite::ClampIterationCount { max }, Token::Ident(ref ident))
+            if depth == 0 && ident.eq_ignore_ascii_case("infinite") =>
+        {
+            number(*max)
+        }
+        (_, token) => token,
+    }
+}
+
+fn number<'i>(value: f32) -> Token<'i> {
+    Token::Number {
+        value,
+        has_sign: false,
+        int_value: int_value(value),
+    }
+}
+
+fn dimension(value: f32, unit: CowRcStr) -> Token {
+    if &*unit == "%" {
+        return Token::Percentage {
+            unit_value: value / 100.,
+            has_sign: false,
+            int_value: int_value(value),
+        };
+    }
+    Token::Dimension {
+        value,
+        unit,
+        has_sign: false,
+        int_value: int_value(value),
+    }
+}
+
+// Lets whole numbers serialize without a trailing `.0`.
+fn int_value(value: f32) -> Option<i32> {
+    if value.fract() == 0. {
+        Some(value as i32)
+    } else {
+        None
+    }
+}
+
+struct CssValueValidator<'a> {
+    allowed_types: Option<&'a HashSet<CssValueType>>,
+    is_allowed_url: &'a dyn Fn(&str) -> bool,
+}
+
+impl<'a> CssValueValidator<'a> {
+    fn allows(&self, value_type: CssValueType) -> bool {
+        self.allowed_types
+            .is_none_or(|types| types.contains(&value_type))
+    }
+
+    fn allows_keyword(&self, keyword: &str) -> bool {
+        self.allowed_types.is_none_or(|types| {
+            types.iter().any(|value_type| match value_type {
+                CssValueType::Keyword(allowed) => allowed.eq_ignore_ascii_case(keyword),
+                _ => false,
+            })
+        })
+    }
+
+    fn allows_function(&self, function: &str) -> bool {
+        if contains_ignore_case(DANGEROUS_FUNCTIONS, function) {
+            return false;
+        }
+        if contains_ignore_case(COLOR_FUNCTIONS, function) && self.allows(CssValueType::Color) {
+            return true;
+        }
+        self.allowed_types.is_none_or(|types| {
+            types.iter().any(|value_type| match value_type {
+                CssValueType::Function(allowed) => allowed.eq_ignore_ascii_case(function),
+                _ => false,
+            })
+        })
+    }
+
+    // `function` is the name of the function whose arguments are being checked, if any. Inside a
+    // function only nested functions and URLs are checked against the property's grammar, since
+    // arguments like the numbers in `rgb()` or the operators in `calc()` belong to the function.
+    fn is_allowed<'i>(&self, input: &mut Parser<'i, '_>, function: Option<&str>) -> bool {
+        let in_function = function.is_some();
+        let strings_are_urls =
+            function.is_some_and(|name| contains_ignore_case(URL_FUNCTIONS, name));
+
+        while let Ok(token) = input.next_including_whitespace_and_comments() {
+            if is_escape_hack(token) {
+                return false;
+            }
+            let allowed = match token.clone() {
+                Token::WhiteSpace(_) | Token::Comment(_) | Token::Comma => true,
+                Token::Delim('!') if !in_function => match input.next() {
+                    Ok(Token::Ident(ident)) => ident.eq_ignore_ascii_case("important"),
+                    _ => false,
+                },
+                Token::Delim(delim) => {
+                    delim == '/'
+                        || self.allowed_types.is_none()
+                        || (in_function && (delim == '+' || delim == '-' || delim == '*'))
+                }
+                Token::Ident(ident) => {
+                    in_function
+                        || contains_ignore_case(CSS_WIDE_KEYWORDS, &ident)
+                        || self.allows_keyword(&ident)
+                        || (self.allows(CssValueType::Color) && parse_color_keyword(&ident).is_ok())
+                }
+                Token::Number {
+                    int_value, value, ..
+                } => {
+                    in_function
+                        || self.allows(CssValueType::Number)
+                        || (int_value.is_some() && self.allows(CssValueType::Integer))
+                        || (value == 0. && self.allows(CssValueType::Length))
+                }
+                Token::Percentage { .. } => in_function || self.allows(CssValueType::Percentage),
+                Token::Dimension { ref unit, .. } => {
+                    in_function
+                        || self.allowed_types.is_none()
+                        || (contains_ignore_case(LENGTH_UNITS, unit)
+                            && self.allows(CssValueType::Length))
+                        || (contains_ignore_case(ANGLE_UNITS, unit)
+                            && self.allows(CssValueType::Angle))
+                        || (contains_ignore_case(TIME_UNITS, unit)
+                            && self.allows(CssValueType::Time))
+                }
+                Token::Hash(_) | Token::IDHash(_) => {
+                    in_function || self.allows(CssValueType::Color)
+                }
+                Token::QuotedString(string) => {
+                    if strings_are_urls {
+                        (self.is_allowed_url)(&string)
+                    } else {
+                        in_function || self.allows(CssValueType::String)
+                    }
+                }
+                Token::UnquotedUrl(url) => {
+                    self.allows(CssValueType::Url) && (self.is_allowed_url)(&url)
+                }
+                Token::Function(name) => {
+                    if name.eq_ignore_ascii_case("url") {
+                        self.allows(CssValueType::Url)
+                            && input
+                                .parse_nested_block(|input| {
+                                    let url = input.expect_string()?.clone();
+                                    input.expect_exhausted()?;
+                                    Ok::<_, ParseError<'i, ()>>(url)
+                                })
+                                .is_ok_and(|url| (self.is_allowed_url)(&url))
+                    } else {
+                        self.allows_function(&name)
+                            && input
+                                .parse_nested_block(|input| {
+                                    Ok::<_, ParseError<'i, ()>>(self.is_allowed(input, Some(&name)))
+                                })
+                                .unwrap_or(false)
+                    }
+                }
+                Token::ParenthesisBlock | Token::SquareBracketBlock
+                    if in_function || self.allowed_types.is_none() =>
+                {
+                    input
+                        .parse_nested_block(|input| {
+                            Ok::<_, ParseError<'i, ()>>(self.is_allowed(input, function))
+                        })
+                        .unwrap_or(false)
+                }
+                _ => false,
+            };
+
+            if !allowed {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Escapes like the `\9` in `color: red\9` were only ever used to target old versions of IE. They
+// decode to control characters, or to U+FFFD for `\0`.
+fn is_escape_hack(token: &Token) -> bool {
+    match token {
+        Token::Ident(name)
+        | Token::Function(name)
+        | Token::Hash(name)
+        | Token::IDHash(name)
+        | Token::Dimension { unit: name, .. } => {
+            name.chars().any(|c| c.is_control() || c == '\u{FFFD}')
+        }
+        _ => false,
+    }
+}
+
+fn contains_ignore_case(list: &[&str], value: &str) -> bool {
+    list.iter().any(|item| item.eq_ignore_ascii_case(value))
+}
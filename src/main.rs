@@ -7,6 +7,7 @@ extern crate html5ever;
 extern crate maplit;
 #[macro_use]
 extern crate cssparser;
+extern crate selectors;
 extern crate string_cache;
 extern crate typed_arena;
 
@@ -23,15 +24,19 @@ mod css_at_rule {
 
 mod arena_dom;
 mod config;
+mod css_condition;
 mod css_parser;
+mod css_selector;
+mod css_value;
 mod sanitizer;
 
-use arena_dom::{create_element, Arena, NodeData, Ref};
+use arena_dom::{create_element, Arena, Ref};
 use config::basic::BASIC_CONFIG;
 use sanitizer::Sanitizer;
 
 fn main() {
-    let sanitizer = Sanitizer::new(&BASIC_CONFIG, vec![&add_spacer_elements_around_ul]);
+    let mut sanitizer = Sanitizer::new(&BASIC_CONFIG, vec![]);
+    sanitizer.on_element("ul", &add_spacer_elements_around_ul);
     sanitizer
         .sanitize_fragment(&mut io::stdin(), &mut io::stdout())
         .unwrap();
@@ -48,10 +53,6 @@ fn main() {
 // TODO: find a way to avoid passing the arena to transformer functions. It's an implementation
 // detail that doesn't need to be exposed. Also, it's only needed for creating new elements.
 fn add_spacer_elements_around_ul<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
-    if let NodeData::Element { ref name, .. } = node.data {
-        if let local_name!("ul") = name.local {
-            node.insert_before(create_element(arena, "spacer"));
-            node.insert_after(create_element(arena, "spacer"));
-        }
-    }
+    node.insert_before(create_element(arena, "spacer"));
+    node.insert_after(create_element(arena, "spacer"));
 }
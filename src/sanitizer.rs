@@ -1,22 +1,54 @@
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
-use std::io::{Error, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 use url::{ParseError, Url};
 
+use cssparser::{Parser as CssParser, ParserInput, ToCss, Token};
 use html5ever::interface::tree_builder::QuirksMode;
 use html5ever::tendril::{format_tendril, StrTendril, TendrilSink};
-use html5ever::{
-    parse_document, parse_fragment, serialize, Attribute as HTML5everAttribute, LocalName, QualName,
-};
+use html5ever::{parse_document, parse_fragment, serialize, LocalName, QualName};
+use selectors::attr::ParsedAttrSelectorOperation;
+use selectors::parser::Component;
 
-use crate::arena_dom::{Arena, Attribute, Node, NodeData, Ref, Sink, StyleAttribute};
+use crate::arena_dom::{
+    create_text, set_text_attribute, Arena, Attribute, Node, NodeData, Ref, Sink, StyleAttribute,
+};
 use crate::css_at_rule::CssAtRule;
-use crate::css_parser::{parse_css_style_attribute, parse_css_stylesheet, CssRule, CssStyleRule};
+use crate::css_condition::{CssMediaQuery, CssSupportsCondition};
+use crate::css_parser::{
+    parse_css_style_attribute, parse_css_stylesheet, serialize_css_declarations, serialize_tokens,
+    strip_css_comments, CssAtRuleBlock, CssAtRulePrelude, CssDeclaration, CssKeyframe, CssRule,
+    CssSerialization, CssStyleRule, CssValue,
+};
 use crate::css_property::CssProperty;
+use crate::css_selector::{
+    is_static_css_selector, matches_css_selector, parse_css_selector_list, parse_css_selectors,
+    selector_components, CssSelector,
+};
+use crate::css_value::{
+    css_var_references, is_allowed_css_value, resolve_css_var_references, rewrite_css_value,
+    CssValueRewrite, CssValueType, CssVarPolicy,
+};
+
+// How many times `round_trip_check` will re-parse and re-sanitize the output before giving up.
+const MAX_ROUND_TRIPS: usize = 5;
+
+pub type Transformer<'arena> = &'arena dyn Fn(Ref<'arena>, Arena<'arena>);
 
 pub struct Sanitizer<'arena> {
     arena: typed_arena::Arena<Node<'arena>>,
     config: &'arena SanitizerConfig,
-    transformers: Vec<&'arena dyn Fn(Ref<'arena>, Arena<'arena>)>,
+    transformers: Vec<Transformer<'arena>>,
+    before_transformers: Vec<Transformer<'arena>>,
+    // Nodes the `before_transformers` have already been called for, so that a node they moved
+    // under a wrapper isn't transformed again once the walk reaches it there.
+    before_transformed: RefCell<HashSet<*const Node<'arena>>>,
+    leave_transformers: Vec<Transformer<'arena>>,
+    element_transformers: HashMap<LocalName, Vec<Transformer<'arena>>>,
+    selector_transformers: Vec<(Vec<CssSelector>, Transformer<'arena>)>,
+    // Whether `traverse` calls the transformers, which it doesn't while re-sanitizing for the
+    // round trip check.
+    transforming: Cell<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,17 +56,45 @@ pub struct SanitizerConfig {
     pub allow_comments: bool,
     pub allow_doctype: bool,
     pub allowed_elements: HashSet<LocalName>,
+    pub rename_elements: HashMap<LocalName, ElementRename>,
     pub allowed_attributes: HashSet<LocalName>,
     pub allowed_attributes_per_element: HashMap<LocalName, HashSet<LocalName>>,
     pub add_attributes: HashMap<LocalName, &'static str>,
     pub add_attributes_per_element: HashMap<LocalName, HashMap<LocalName, &'static str>>,
     pub allowed_protocols: HashMap<LocalName, HashMap<LocalName, HashSet<Protocol<'static>>>>,
+    pub allowed_svg_elements: HashSet<LocalName>,
+    pub allowed_svg_attributes: HashSet<LocalName>,
+    pub allowed_svg_protocols: HashMap<LocalName, HashSet<Protocol<'static>>>,
+    pub allowed_mathml_elements: HashSet<LocalName>,
+    pub allowed_mathml_attributes: HashSet<LocalName>,
     pub allowed_css_at_rules: HashSet<CssAtRule>,
     pub allowed_css_properties: HashSet<CssProperty>,
+    pub allowed_css_custom_property_prefixes: HashSet<&'static str>,
+    pub css_var_policy: CssVarPolicy,
+    pub allowed_css_values: HashMap<CssProperty, HashSet<CssValueType>>,
+    pub rewrite_css_values: Vec<CssValueRewrite>,
+    pub rewrite_css_values_per_property: HashMap<CssProperty, Vec<CssValueRewrite>>,
     pub allowed_css_protocols: HashSet<Protocol<'static>>,
+    pub allowed_css_hosts: Option<HashSet<&'static str>>,
+    pub allowed_css_media_types: HashSet<&'static str>,
+    pub allowed_css_media_features: HashSet<&'static str>,
     pub allow_css_comments: bool,
+    pub allowed_css_selector_attributes: HashSet<LocalName>,
+    pub allow_css_universal_selector: bool,
+    pub allow_css_root_selectors: bool,
+    pub css_selector_scope: Option<String>,
+    pub id_prefix: Option<String>,
+    pub class_prefix: Option<String>,
+    pub animation_name_prefix: Option<String>,
+    pub css_serialization: CssSerialization,
+    pub inline_css: bool,
+    pub disallowed_element_disposition: ElementDisposition,
+    pub disallowed_element_disposition_per_element: HashMap<LocalName, ElementDisposition>,
     pub remove_contents_when_unwrapped: HashSet<LocalName>,
     pub whitespace_around_unwrapped_content: HashMap<LocalName, ContentWhitespace<'static>>,
+    pub remove_empty_elements: HashSet<LocalName>,
+    pub remove_whitespace_only_elements: bool,
+    pub round_trip_check: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -43,6 +103,41 @@ pub enum Protocol<'a> {
     Relative,
 }
 
+/// What happens to an element that isn't allowed. When disallowed elements are unwrapped, the ones
+/// in `remove_contents_when_unwrapped` are removed instead, unless they have a disposition of their
+/// own in `disallowed_element_disposition_per_element`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ElementDisposition {
+    /// Replaces the element with its sanitized contents.
+    Unwrap,
+    /// Removes the element along with its contents.
+    Remove,
+    /// Keeps the element's start and end tags as text around its sanitized contents, so that
+    /// `<script>` shows up as `&lt;script&gt;`.
+    Escape,
+}
+
+/// What an HTML element is renamed to, e.g. `<font>` to `<span>`, before it is checked against
+/// `allowed_elements`. The attributes in `attributes_to_css` are moved into the `style` attribute
+/// as the given property, e.g. `color` on `<font>` to `color`, and `css` is added to it, e.g.
+/// `text-align: center` for `<center>`. The style attribute is then sanitized as usual.
+#[derive(Debug, Clone)]
+pub struct ElementRename {
+    pub name: LocalName,
+    pub attributes_to_css: HashMap<LocalName, CssProperty>,
+    pub css: Option<&'static str>,
+}
+
+impl ElementRename {
+    pub fn to(name: LocalName) -> ElementRename {
+        ElementRename {
+            name,
+            attributes_to_css: HashMap::new(),
+            css: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ContentWhitespace<'a> {
     before: &'a str,
@@ -61,15 +156,60 @@ impl<'a> ContentWhitespace<'a> {
 impl<'arena> Sanitizer<'arena> {
     pub fn new(
         config: &'arena SanitizerConfig,
-        transformers: Vec<&'arena dyn Fn(Ref<'arena>, Arena<'arena>)>,
+        transformers: Vec<Transformer<'arena>>,
     ) -> Sanitizer<'arena> {
         Sanitizer {
             arena: typed_arena::Arena::new(),
             config,
             transformers,
+            before_transformers: Vec::new(),
+            before_transformed: RefCell::new(HashSet::new()),
+            leave_transformers: Vec::new(),
+            element_transformers: HashMap::new(),
+            selector_transformers: Vec::new(),
+            transforming: Cell::new(true),
         }
     }
 
+    /// Registers a transformer that is called for each node before it is sanitized, so it can
+    /// change or replace an element that would otherwise be unwrapped or lose its attributes. If it
+    /// replaces the node, the walk carries on with whatever took its place.
+    pub fn before_sanitize(&mut self, transformer: Transformer<'arena>) -> &mut Self {
+        self.before_transformers.push(transformer);
+        self
+    }
+
+    /// Registers a transformer that is called for each node after everything under it has been
+    /// sanitized and transformed, where the transformers passed to `Sanitizer::new` are called
+    /// before. Nodes it inserts after the one being transformed are sanitized, the rest are not.
+    pub fn on_leave(&mut self, transformer: Transformer<'arena>) -> &mut Self {
+        self.leave_transformers.push(transformer);
+        self
+    }
+
+    /// Registers a transformer that is only called for elements matching any of the
+    /// comma-separated `selectors`, after the transformers passed to `Sanitizer::new`.
+    ///
+    /// Panics if `selectors` can't be parsed, rather than registering a transformer that never
+    /// runs.
+    pub fn on(&mut self, selectors: &str, transformer: Transformer<'arena>) -> &mut Self {
+        let parsed_selectors = parse_css_selector_list(selectors)
+            .unwrap_or_else(|| panic!("invalid CSS selector list: {:?}", selectors));
+        self.selector_transformers
+            .push((parsed_selectors, transformer));
+        self
+    }
+
+    /// Registers a transformer that is only called for elements named `name`, in any namespace,
+    /// after the transformers passed to `Sanitizer::new`.
+    pub fn on_element(&mut self, name: &str, transformer: Transformer<'arena>) -> &mut Self {
+        self.element_transformers
+            .entry(LocalName::from(name))
+            .or_default()
+            .push(transformer);
+        self
+    }
+
     pub fn sanitize_fragment(
         &'arena self,
         input: &mut impl Read,
@@ -77,7 +217,14 @@ impl<'arena> Sanitizer<'arena> {
     ) -> Result<(), Error> {
         let root = self.parse_fragment(input)?;
         self.traverse(root);
-        serialize(output, root, Default::default())
+        if self.config.inline_css {
+            self.inline_css(root);
+        }
+        if self.config.round_trip_check {
+            self.serialize_round_trip(root, output, |data| self.parse_fragment(data))
+        } else {
+            serialize(output, root, Default::default())
+        }
     }
 
     pub fn sanitize_document(
@@ -87,7 +234,47 @@ impl<'arena> Sanitizer<'arena> {
     ) -> Result<(), Error> {
         let root = self.parse_document(input)?;
         self.traverse(root);
-        serialize(output, root, Default::default())
+        if self.config.inline_css {
+            self.inline_css(root);
+        }
+        if self.config.round_trip_check {
+            self.serialize_round_trip(root, output, |data| self.parse_document(data))
+        } else {
+            serialize(output, root, Default::default())
+        }
+    }
+
+    // A browser will not always parse serialized HTML back into the tree it was serialized from
+    // (mutation XSS), so keep re-parsing and re-sanitizing the output until it stops changing.
+    fn serialize_round_trip(
+        &'arena self,
+        root: Ref<'arena>,
+        output: &mut impl Write,
+        parse: impl Fn(&mut &[u8]) -> Result<Ref<'arena>, Error>,
+    ) -> Result<(), Error> {
+        let mut serialized = Vec::new();
+        serialize(&mut serialized, root, Default::default())?;
+
+        // Only the sanitizer's own passes need to hold steady. The transformers have had their say,
+        // and one that isn't idempotent would never let the output settle.
+        for _ in 0..MAX_ROUND_TRIPS {
+            let reparsed_root = parse(&mut &serialized[..])?;
+            self.transforming.set(false);
+            self.traverse(reparsed_root);
+            self.transforming.set(true);
+            let mut reserialized = Vec::new();
+            serialize(&mut reserialized, reparsed_root, Default::default())?;
+
+            if reserialized == serialized {
+                return output.write_all(&serialized);
+            }
+            serialized = reserialized;
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "sanitized output did not parse back into the same tree",
+        ))
     }
 
     fn parse_document(&'arena self, data: &mut impl Read) -> Result<Ref<'arena>, Error> {
@@ -114,7 +301,7 @@ impl<'arena> Sanitizer<'arena> {
         Ok(parse_fragment(
             sink,
             Default::default(),
-            QualName::new(None, ns!(), local_name!("body")),
+            QualName::new(None, ns!(html), local_name!("body")),
             vec![],
         )
         .from_utf8()
@@ -122,14 +309,58 @@ impl<'arena> Sanitizer<'arena> {
     }
 
     fn traverse(&'arena self, node: Ref<'arena>) {
+        if node.trusted.get() {
+            if let Some(sibling) = node.next_sibling.get() {
+                self.traverse(sibling);
+            }
+            return;
+        }
+
+        if self.transforming.get()
+            && !self.before_transformers.is_empty()
+            && self.before_transformed.borrow_mut().insert(node)
+        {
+            let parent = node.parent.get();
+            let previous_sibling = node.previous_sibling.get();
+            let next_sibling = node.next_sibling.get();
+            for transformer in self.before_transformers.iter() {
+                transformer(node, &self.arena);
+            }
+
+            // If the node was removed, replaced, wrapped or moved, carry on with whatever is in its
+            // place now so that nothing after it is skipped.
+            if !is_same_node(node.parent.get(), parent)
+                || !is_same_node(node.previous_sibling.get(), previous_sibling)
+            {
+                let in_place = match previous_sibling {
+                    Some(previous_sibling) => previous_sibling.next_sibling.get(),
+                    None => parent.and_then(|parent| parent.first_child.get()),
+                };
+                if let Some(next) = in_place.or(next_sibling) {
+                    self.traverse(next);
+                }
+                return;
+            }
+        }
+
+        let node = self.rename_element(node);
         if self.should_unwrap_node(node) {
             let sibling = node.next_sibling.get();
 
-            if self.should_remove_contents_when_unwrapped(node) {
-                node.detach();
-            } else if let Some(unwrapped_node) = node.unwrap() {
-                self.add_unwrapped_content_whitespace(node, unwrapped_node);
-                self.traverse(unwrapped_node);
+            match self.disallowed_element_disposition(node) {
+                ElementDisposition::Remove => node.detach(),
+                ElementDisposition::Unwrap => {
+                    if let Some(unwrapped_node) = node.unwrap() {
+                        self.add_unwrapped_content_whitespace(node, unwrapped_node);
+                        self.traverse(unwrapped_node);
+                    }
+                }
+                ElementDisposition::Escape => {
+                    self.escape_element(node);
+                    if let Some(unwrapped_node) = node.unwrap() {
+                        self.traverse(unwrapped_node);
+                    }
+                }
             }
 
             if let Some(sibling) = sibling {
@@ -141,23 +372,122 @@ impl<'arena> Sanitizer<'arena> {
 
         self.remove_attributes(node);
         self.add_attributes(node);
+        self.prefix_attributes(node);
         self.sanitize_attribute_protocols(node);
         self.sanitize_style_tag_css(node);
         self.sanitize_style_attribute_css(node);
 
-        for transformer in self.transformers.iter() {
-            transformer(node, &self.arena);
+        if self.transforming.get() {
+            for transformer in self.transformers.iter() {
+                transformer(node, &self.arena);
+            }
+            self.run_element_transformers(node);
+        }
+
+        // The children of a `<template>` are parsed into a separate document fragment instead of
+        // being appended to the element itself.
+        if let NodeData::Element {
+            template_contents: Some(contents),
+            ..
+        } = node.data
+        {
+            if let Some(child) = contents.first_child.get() {
+                self.traverse(child);
+            }
         }
 
         if let Some(child) = node.first_child.get() {
             self.traverse(child);
         }
 
-        if let Some(sibling) = node.next_sibling.get() {
+        // Removing the node, or a leave transformer doing so, shouldn't end the walk over its
+        // siblings.
+        let sibling = node.next_sibling.get();
+        if self.should_remove_empty_element(node) {
+            node.detach();
+        } else if self.transforming.get() {
+            for transformer in self.leave_transformers.iter() {
+                transformer(node, &self.arena);
+            }
+        }
+
+        if let Some(sibling) = node.next_sibling.get().or(sibling) {
             self.traverse(sibling);
         }
     }
 
+    fn run_element_transformers(&'arena self, node: Ref<'arena>) {
+        if let NodeData::Element { ref name, .. } = node.data {
+            if let Some(transformers) = self.element_transformers.get(&name.local) {
+                for transformer in transformers.iter() {
+                    transformer(node, &self.arena);
+                }
+            }
+            for (selectors, transformer) in self.selector_transformers.iter() {
+                if selectors
+                    .iter()
+                    .any(|selector| matches_css_selector(selector, node))
+                {
+                    transformer(node, &self.arena);
+                }
+            }
+        }
+    }
+
+    // Returns the renamed element that took `node`'s place, or `node` if it wasn't renamed.
+    fn rename_element(&'arena self, node: Ref<'arena>) -> Ref<'arena> {
+        if let NodeData::Element {
+            ref name,
+            ref attrs,
+            template_contents,
+            mathml_annotation_xml_integration_point,
+        } = node.data
+        {
+            let rename = match self.config.rename_elements.get(&name.local) {
+                Some(rename) if name.ns == ns!(html) => rename,
+                _ => return node,
+            };
+
+            let mut attrs = attrs.take();
+            let mut css: Vec<String> = rename.css.iter().map(|css| css.to_string()).collect();
+            attrs.retain(|attr| match attr {
+                Attribute::Text(attr) => match rename.attributes_to_css.get(&attr.name.local) {
+                    Some(property) => {
+                        // The value must not be able to end the declaration and start another.
+                        if !attr.value.contains(&[';', '{', '}', '!'][..]) {
+                            css.push(format!("{}: {}", property, attr.value));
+                        }
+                        false
+                    }
+                    None => true,
+                },
+                Attribute::Style(_) => true,
+            });
+            if !css.is_empty() {
+                // Whatever was already in the style attribute goes last so that it wins.
+                for attr in attrs.iter() {
+                    if attr.name().local == local_name!("style") {
+                        css.push(attr.value().into_owned());
+                    }
+                }
+                set_text_attribute(&mut attrs, &local_name!("style"), &css.join("; "));
+            }
+
+            let renamed = self.arena.alloc(Node::new(NodeData::Element {
+                name: QualName::new(name.prefix.clone(), ns!(html), rename.name.clone()),
+                attrs: RefCell::new(attrs),
+                template_contents,
+                mathml_annotation_xml_integration_point,
+            }));
+            while let Some(child) = node.first_child.get() {
+                renamed.append(child);
+            }
+            node.replace_with(renamed);
+            return renamed;
+        }
+        node
+    }
+
     fn should_unwrap_node(&self, node: Ref) -> bool {
         match node.data {
             NodeData::Document
@@ -167,23 +497,109 @@ impl<'arena> Sanitizer<'arena> {
             NodeData::Comment { .. } => !self.config.allow_comments,
             NodeData::Doctype { .. } => !self.config.allow_doctype,
             NodeData::Element { ref name, .. } => {
-                !self.config.allowed_elements.contains(&name.local)
+                !self.is_allowed_element(name) || !has_valid_namespace(node) || animates_href(node)
+            }
+        }
+    }
+
+    // Elements are only checked against the allowlist of their own namespace so that, for
+    // example, allowing the HTML `<a>` element does not also allow the SVG `<a>` element.
+    fn is_allowed_element(&self, name: &QualName) -> bool {
+        match name.ns {
+            ns!(html) => self.config.allowed_elements.contains(&name.local),
+            ns!(svg) => self.config.allowed_svg_elements.contains(&name.local),
+            ns!(mathml) => self.config.allowed_mathml_elements.contains(&name.local),
+            _ => false,
+        }
+    }
+
+    // Called once the node's children have been sanitized, so that an element left empty by
+    // removing its contents is removed too. Comments don't count as content.
+    fn should_remove_empty_element(&self, node: Ref) -> bool {
+        match node.data {
+            NodeData::Element { ref name, .. }
+                if self.config.remove_empty_elements.contains(&name.local)
+                    && !is_void_element(&name.local) =>
+            {
+                node.children().all(|child| match child.data {
+                    NodeData::Text { ref contents } => {
+                        let contents = contents.borrow();
+                        contents.is_empty()
+                            || (self.config.remove_whitespace_only_elements
+                                && contents.trim().is_empty())
+                    }
+                    NodeData::Comment { .. } | NodeData::ProcessingInstruction { .. } => true,
+                    _ => false,
+                })
             }
+            _ => false,
         }
     }
 
-    fn should_remove_contents_when_unwrapped(&self, node: Ref) -> bool {
+    fn disallowed_element_disposition(&self, node: Ref) -> ElementDisposition {
         match node.data {
             NodeData::Document
             | NodeData::Doctype { .. }
             | NodeData::Text { .. }
             | NodeData::StyleSheet { .. }
             | NodeData::ProcessingInstruction { .. }
-            | NodeData::Comment { .. } => false,
-            NodeData::Element { ref name, .. } => self
-                .config
-                .remove_contents_when_unwrapped
-                .contains(&name.local),
+            | NodeData::Comment { .. } => ElementDisposition::Unwrap,
+            NodeData::Element { ref name, .. } => {
+                // An allowed element in the wrong namespace would be something else once re-parsed,
+                // so it's removed. Disallowed ones are unwrapped as usual.
+                if self.is_allowed_element(name) && !has_valid_namespace(node) {
+                    return ElementDisposition::Remove;
+                }
+                if animates_href(node) {
+                    return ElementDisposition::Remove;
+                }
+                match self
+                    .config
+                    .disallowed_element_disposition_per_element
+                    .get(&name.local)
+                {
+                    Some(&disposition) => disposition,
+                    None if self.config.disallowed_element_disposition
+                        == ElementDisposition::Unwrap
+                        && self
+                            .config
+                            .remove_contents_when_unwrapped
+                            .contains(&name.local) =>
+                    {
+                        ElementDisposition::Remove
+                    }
+                    None => self.config.disallowed_element_disposition,
+                }
+            }
+        }
+    }
+
+    // Surrounds the element with text nodes of its start and end tags, attributes included, to be
+    // left behind when it's unwrapped.
+    fn escape_element(&'arena self, node: Ref<'arena>) {
+        if let NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } = node.data
+        {
+            let mut start_tag = format!("<{}", name.local);
+            for attr in attrs.borrow().iter() {
+                let name = attr.name();
+                let value = attr.value();
+                let quote = if value.contains('"') { '\'' } else { '"' };
+                start_tag.push(' ');
+                if let Some(ref prefix) = name.prefix {
+                    start_tag.push_str(&format!("{}:", prefix));
+                }
+                start_tag.push_str(&format!("{}={}{}{}", name.local, quote, value, quote));
+            }
+            start_tag.push('>');
+            node.insert_before(create_text(&self.arena, &start_tag));
+
+            if !is_void_element(&name.local) {
+                node.insert_after(create_text(&self.arena, &format!("</{}>", name.local)));
+            }
         }
     }
 
@@ -194,24 +610,24 @@ impl<'arena> Sanitizer<'arena> {
             ..
         } = node.data
         {
-            let attrs = &mut attrs.borrow_mut();
-            let mut i = 0;
-            let all_allowed = &self.config.allowed_attributes;
-            let per_element_allowed = self.config.allowed_attributes_per_element.get(&name.local);
-            while i != attrs.len() {
-                if let Attribute::Text(attr) = &attrs[i] {
-                    if !all_allowed.contains(&attr.name.local) {
-                        if let Some(per_element_allowed) = per_element_allowed {
-                            if per_element_allowed.contains(&attr.name.local) {
-                                i += 1;
-                                continue;
-                            }
-                        }
-                        attrs.remove(i);
-                        continue;
-                    }
-                }
-                i += 1;
+            attrs.borrow_mut().retain(|attr| match attr {
+                Attribute::Text(attr) => self.is_allowed_attribute(name, &attr.name.local),
+                Attribute::Style(_) => true,
+            });
+        }
+    }
+
+    fn is_allowed_attribute(&self, element: &QualName, attribute: &LocalName) -> bool {
+        match element.ns {
+            ns!(svg) => self.config.allowed_svg_attributes.contains(attribute),
+            ns!(mathml) => self.config.allowed_mathml_attributes.contains(attribute),
+            _ => {
+                self.config.allowed_attributes.contains(attribute)
+                    || self
+                        .config
+                        .allowed_attributes_per_element
+                        .get(&element.local)
+                        .is_some_and(|allowed| allowed.contains(attribute))
             }
         }
     }
@@ -223,24 +639,55 @@ impl<'arena> Sanitizer<'arena> {
             ..
         } = node.data
         {
+            if name.ns != ns!(html) {
+                return;
+            }
+
             let attrs = &mut attrs.borrow_mut();
             let add_attributes = &self.config.add_attributes;
             let add_attributes_per_element =
                 self.config.add_attributes_per_element.get(&name.local);
 
             for (name, &value) in add_attributes.iter() {
-                attrs.push(Attribute::Text(HTML5everAttribute {
-                    name: QualName::new(None, ns!(), name.clone()),
-                    value: StrTendril::from(value),
-                }));
+                set_text_attribute(attrs, name, value);
             }
 
             if let Some(add_attributes_per_element) = add_attributes_per_element {
                 for (name, &value) in add_attributes_per_element.iter() {
-                    attrs.push(Attribute::Text(HTML5everAttribute {
-                        name: QualName::new(None, ns!(), name.clone()),
-                        value: StrTendril::from(value),
-                    }));
+                    set_text_attribute(attrs, name, value);
+                }
+            }
+        }
+    }
+
+    // Namespaces IDs, names and classes so that they can't clobber globals or collide with the
+    // page the sanitized HTML is embedded into. Values that already have the prefix are left alone
+    // so that sanitizing the output again doesn't change it.
+    fn prefix_attributes(&self, node: Ref<'arena>) {
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            let id_prefix = self.config.id_prefix.as_deref();
+            let class_prefix = self.config.class_prefix.as_deref();
+
+            for attr in attrs.borrow_mut().iter_mut() {
+                if let Attribute::Text(attr) = attr {
+                    let value = match (&attr.name.local, id_prefix, class_prefix) {
+                        (&local_name!("id"), Some(prefix), _)
+                        | (&local_name!("name"), Some(prefix), _) => {
+                            add_prefix(&attr.value, prefix)
+                        }
+                        (&local_name!("for"), Some(prefix), _)
+                        | (&local_name!("headers"), Some(prefix), _) => {
+                            add_prefix_to_tokens(&attr.value, prefix)
+                        }
+                        (&local_name!("href"), Some(prefix), _) if attr.value.starts_with('#') => {
+                            format!("#{}", add_prefix(&attr.value[1..], prefix))
+                        }
+                        (&local_name!("class"), _, Some(prefix)) => {
+                            add_prefix_to_tokens(&attr.value, prefix)
+                        }
+                        _ => continue,
+                    };
+                    attr.value = StrTendril::from(value);
                 }
             }
         }
@@ -255,30 +702,20 @@ impl<'arena> Sanitizer<'arena> {
         {
             let attrs = &mut attrs.borrow_mut();
 
-            if let Some(protocols) = self.config.allowed_protocols.get(&name.local) {
+            let protocols = match name.ns {
+                ns!(svg) => Some(&self.config.allowed_svg_protocols),
+                _ => self.config.allowed_protocols.get(&name.local),
+            };
+
+            if let Some(protocols) = protocols {
                 let mut i = 0;
                 while i != attrs.len() {
                     if let Attribute::Text(attr) = &attrs[i] {
                         if let Some(allowed_protocols) = protocols.get(&attr.name.local) {
-                            match Url::parse(&attr.value) {
-                                Ok(url) => {
-                                    if !allowed_protocols.contains(&Protocol::Scheme(url.scheme()))
-                                    {
-                                        attrs.remove(i);
-                                    } else {
-                                        i += 1;
-                                    }
-                                }
-                                Err(ParseError::RelativeUrlWithoutBase) => {
-                                    if !allowed_protocols.contains(&Protocol::Relative) {
-                                        attrs.remove(i);
-                                    } else {
-                                        i += 1;
-                                    }
-                                }
-                                Err(_) => {
-                                    attrs.remove(i);
-                                }
+                            if !is_allowed_url(&attr.value, allowed_protocols) {
+                                attrs.remove(i);
+                            } else {
+                                i += 1;
                             }
                         } else {
                             i += 1;
@@ -291,122 +728,1003 @@ impl<'arena> Sanitizer<'arena> {
         }
     }
 
-    fn sanitize_css_rules(&self, rules: Vec<CssRule>) -> Vec<CssRule> {
+    // `custom_properties` are the ones `var()` references are resolved against when
+    // `css_var_policy` is `CssVarPolicy::Resolve`.
+    fn sanitize_css_rules(
+        &self,
+        rules: Vec<CssRule>,
+        custom_properties: &HashMap<String, CssValue>,
+    ) -> Vec<CssRule> {
         rules
             .into_iter()
             .filter_map(|rule| match rule {
                 CssRule::StyleRule(style_rule) => Some(CssRule::StyleRule(CssStyleRule {
-                    selectors: style_rule.selectors,
-                    declarations: style_rule
-                        .declarations
-                        .into_iter()
-                        .filter(|declaration| {
-                            self.config
-                                .allowed_css_properties
-                                .contains(&CssProperty::from(declaration.property.as_str()))
-                        })
-                        .collect(),
+                    selectors: self.sanitize_css_selectors(&style_rule.selectors)?,
+                    declarations: self
+                        .sanitize_css_declarations(style_rule.declarations, custom_properties),
+                    span: style_rule.span,
                 })),
-                CssRule::AtRule(at_rule) => {
-                    if self
-                        .config
-                        .allowed_css_at_rules
-                        .contains(&CssAtRule::from(at_rule.name.as_str()))
+                CssRule::AtRule(mut at_rule) => {
+                    let at_rule_name = CssAtRule::from(at_rule.name.as_str());
+                    if !self.config.allowed_css_at_rules.contains(&at_rule_name) {
+                        return None;
+                    }
+                    at_rule.prelude = match (at_rule_name, at_rule.prelude) {
+                        (css_at_rule!("import"), CssAtRulePrelude::Import { url, conditions }) => {
+                            if !self.is_allowed_css_url(&url) {
+                                return None;
+                            }
+                            CssAtRulePrelude::Import { url, conditions }
+                        }
+                        (css_at_rule!("media"), CssAtRulePrelude::Media(queries)) => {
+                            CssAtRulePrelude::Media(self.sanitize_css_media_queries(queries)?)
+                        }
+                        (css_at_rule!("supports"), CssAtRulePrelude::Supports(condition)) => {
+                            if !self.is_allowed_css_supports_condition(&condition) {
+                                return None;
+                            }
+                            CssAtRulePrelude::Supports(condition)
+                        }
+                        (
+                            css_at_rule!("import")
+                            | css_at_rule!("media")
+                            | css_at_rule!("supports"),
+                            _,
+                        ) => return None,
+                        (_, prelude) => prelude,
+                    };
+
+                    at_rule.block = at_rule.block.map(|block| match block {
+                        CssAtRuleBlock::Rules(rules) => {
+                            CssAtRuleBlock::Rules(self.sanitize_css_rules(rules, custom_properties))
+                        }
+                        CssAtRuleBlock::Declarations(declarations) => CssAtRuleBlock::Declarations(
+                            self.sanitize_css_declarations(declarations, custom_properties),
+                        ),
+                        CssAtRuleBlock::Keyframes(keyframes) => CssAtRuleBlock::Keyframes(
+                            self.sanitize_css_keyframes(keyframes, custom_properties),
+                        ),
+                    });
+                    if let (CssAtRulePrelude::Keyframes { ref mut name, .. }, Some(prefix)) =
+                        (&mut at_rule.prelude, &self.config.animation_name_prefix)
                     {
-                        Some(CssRule::AtRule(at_rule))
-                    } else {
-                        None
+                        *name = add_prefix(name, prefix);
                     }
+                    Some(CssRule::AtRule(at_rule))
                 }
             })
             .collect()
     }
 
-    fn sanitize_style_tag_css(&'arena self, node: Ref<'arena>) {
-        if let NodeData::Element { ref name, .. } = node.data {
-            if name.local == local_name!("style") {
-                // TODO: is it okay to assume <style> tags will only ever have one text node child?
-                if let Some(first_child) = node.first_child.take() {
-                    if let NodeData::Text { ref contents, .. } = first_child.data {
-                        let rules = parse_css_stylesheet(&contents.borrow());
-                        let sanitized_rules = self.sanitize_css_rules(rules);
-                        first_child.detach();
-                        let stylesheet = self.arena.alloc(Node::new(NodeData::StyleSheet {
-                            rules: sanitized_rules,
-                        }));
-                        node.append(stylesheet);
-                    }
+    fn sanitize_css_keyframes(
+        &self,
+        keyframes: Vec<CssKeyframe>,
+        custom_properties: &HashMap<String, CssValue>,
+    ) -> Vec<CssKeyframe> {
+        keyframes
+            .into_iter()
+            .map(|keyframe| CssKeyframe {
+                declarations: self
+                    .sanitize_css_declarations(keyframe.declarations, custom_properties),
+                ..keyframe
+            })
+            .collect()
+    }
+
+    fn sanitize_css_selectors(&self, selectors: &str) -> Option<String> {
+        let selectors: Vec<String> = parse_css_selectors(selectors)
+            .iter()
+            .filter(|selector| self.is_allowed_css_selector(selector))
+            .map(|selector| {
+                let selector = prefix_css_selector(
+                    &selector.to_css_string(),
+                    self.config.id_prefix.as_deref(),
+                    self.config.class_prefix.as_deref(),
+                );
+                match self.config.css_selector_scope {
+                    Some(ref scope) => format!("{} {}", scope, selector),
+                    None => selector,
                 }
-            }
+            })
+            .collect();
+
+        if selectors.is_empty() {
+            None
+        } else {
+            Some(selectors.join(", "))
         }
     }
 
-    fn sanitize_style_attribute_css(&self, node: Ref<'arena>) {
-        if let NodeData::Element { ref attrs, .. } = node.data {
-            let mut i = 0;
-            let attrs = &mut attrs.borrow_mut();
+    // Drops the queries that use disallowed media types or features. The whole rule is dropped if
+    // none are left, since an empty query list would match everything.
+    fn sanitize_css_media_queries(
+        &self,
+        queries: Vec<CssMediaQuery>,
+    ) -> Option<Vec<CssMediaQuery>> {
+        if queries.is_empty() {
+            return Some(queries);
+        }
 
-            while i != attrs.len() {
-                if let Attribute::Text(attr) = &attrs[i] {
-                    if attr.name.local == local_name!("style") {
-                        let css_str = &attr.value;
-                        let mut declarations = parse_css_style_attribute(css_str);
-                        declarations.retain(|declaration| {
-                            self.config
-                                .allowed_css_properties
-                                .contains(&CssProperty::from(declaration.property.as_str()))
-                        });
-                        let name = attr.name.clone();
-                        attrs.remove(i);
-                        attrs.insert(
-                            i,
-                            Attribute::Style(StyleAttribute {
-                                name,
-                                value: declarations,
-                                serialized_value: None,
-                            }),
-                        );
-                    }
-                }
-                i += 1;
-            }
+        let queries: Vec<CssMediaQuery> = queries
+            .into_iter()
+            .filter(|query| {
+                query.media_type.as_ref().is_none_or(|media_type| {
+                    self.config
+                        .allowed_css_media_types
+                        .contains(media_type.as_str())
+                }) && query.features.iter().all(|feature| {
+                    self.config
+                        .allowed_css_media_features
+                        .contains(feature.as_str())
+                })
+            })
+            .collect();
+
+        if queries.is_empty() {
+            None
+        } else {
+            Some(queries)
         }
     }
 
-    fn add_unwrapped_content_whitespace(
-        &self,
-        wrapping_node: Ref<'arena>,
-        unwrapped_node: Ref<'arena>,
-    ) {
-        if let NodeData::Element { ref name, .. } = wrapping_node.data {
-            if let Some(content_whitespace) = self
-                .config
-                .whitespace_around_unwrapped_content
-                .get(&name.local)
-            {
-                if let NodeData::Text { ref contents, .. } = unwrapped_node.data {
-                    contents.replace_with(|current| {
-                        format_tendril!(
-                            "{}{}{}",
-                            content_whitespace.before,
-                            current,
-                            content_whitespace.after
-                        )
+    // Feature queries may only test for declarations and selectors that would themselves be
+    // allowed.
+    fn is_allowed_css_supports_condition(&self, condition: &CssSupportsCondition) -> bool {
+        condition.declarations.iter().all(|declaration| {
+            if declaration.property.starts_with("--") {
+                return self.is_allowed_css_custom_property(&declaration.property)
+                    && is_allowed_css_value(&declaration.value.to_css_string(), None, &|url| {
+                        self.is_allowed_css_url(url)
                     });
-                }
             }
-        }
+            let property = CssProperty::from(declaration.property.as_str());
+            self.config.allowed_css_properties.contains(&property)
+                && (css_var_references(&declaration.value).is_empty()
+                    || self
+                        .sanitize_css_var_references(&declaration.value, &HashMap::new())
+                        .is_some())
+                && is_allowed_css_value(
+                    &declaration.value.to_css_string(),
+                    self.config.allowed_css_values.get(&property),
+                    &|url| self.is_allowed_css_url(url),
+                )
+        }) && condition.selectors.iter().all(|selectors| {
+            let parsed_selectors = parse_css_selectors(selectors);
+            !parsed_selectors.is_empty()
+                && parsed_selectors
+                    .iter()
+                    .all(|selector| self.is_allowed_css_selector(selector))
+        })
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
 
-    use std::str;
+    fn is_allowed_css_selector(&self, selector: &CssSelector) -> bool {
+        selector_components(selector)
+            .into_iter()
+            .all(|component| match *component {
+                Component::ExplicitUniversalType => self.config.allow_css_universal_selector,
+                Component::Root => self.config.allow_css_root_selectors,
+                Component::LocalName(ref name) => {
+                    self.config.allow_css_root_selectors
+                        || !matches!(
+                            name.lower_name,
+                            local_name!("body") | local_name!("head") | local_name!("html")
+                        )
+                }
+                Component::AttributeInNoNamespaceExists {
+                    ref local_name_lower,
+                    ..
+                } => self
+                    .config
+                    .allowed_css_selector_attributes
+                    .contains(local_name_lower),
+                Component::AttributeInNoNamespace { ref local_name, .. } => {
+                    self.config
+                        .allowed_css_selector_attributes
+                        .contains(local_name)
+                        && !self.is_prefixed_attribute(local_name)
+                }
+                Component::AttributeOther(ref attr) => {
+                    self.config
+                        .allowed_css_selector_attributes
+                        .contains(&attr.local_name_lower)
+                        && (attr.operation == ParsedAttrSelectorOperation::Exists
+                            || !self.is_prefixed_attribute(&attr.local_name_lower))
+                }
+                Component::Host(_) | Component::Part(_) | Component::Slotted(_) => false,
+                _ => true,
+            })
+    }
 
-    struct MockRead {
-        contents: &'static str,
+    // Whether `prefix_attributes` changes the values of the attribute, so that a selector comparing
+    // them would stop matching.
+    fn is_prefixed_attribute(&self, name: &LocalName) -> bool {
+        match *name {
+            local_name!("id")
+            | local_name!("name")
+            | local_name!("for")
+            | local_name!("headers")
+            | local_name!("href") => self.config.id_prefix.is_some(),
+            local_name!("class") => self.config.class_prefix.is_some(),
+            _ => false,
+        }
+    }
+
+    fn sanitize_css_declarations(
+        &self,
+        declarations: Vec<CssDeclaration>,
+        custom_properties: &HashMap<String, CssValue>,
+    ) -> Vec<CssDeclaration> {
+        declarations
+            .into_iter()
+            .filter_map(|mut declaration| {
+                if declaration.property.starts_with("--") {
+                    return self.sanitize_css_custom_property(declaration, custom_properties);
+                }
+                let property = CssProperty::from(declaration.property.as_str());
+                if !self.config.allowed_css_properties.contains(&property) {
+                    return None;
+                }
+                let rewrites: Vec<&CssValueRewrite> = self
+                    .config
+                    .rewrite_css_values
+                    .iter()
+                    .chain(
+                        self.config
+                            .rewrite_css_values_per_property
+                            .get(&property)
+                            .into_iter()
+                            .flatten(),
+                    )
+                    .collect();
+                if !css_var_references(&declaration.value).is_empty() {
+                    // A `var()` that's left in would hide its value from the rewrites.
+                    if self.config.css_var_policy == CssVarPolicy::Allow
+                        && rewrites
+                            .iter()
+                            .any(|rewrite| **rewrite != CssValueRewrite::StripImportant)
+                    {
+                        return None;
+                    }
+                    declaration.value =
+                        self.sanitize_css_var_references(&declaration.value, custom_properties)?;
+                }
+
+                let mut value = declaration.value.to_css_string();
+                if !rewrites.is_empty() {
+                    value = rewrite_css_value(&value, &rewrites)?;
+                    if rewrites.contains(&&CssValueRewrite::StripImportant) {
+                        declaration.important = false;
+                    }
+                }
+                if let Some(ref prefix) = self.config.animation_name_prefix {
+                    if property == css_property!("animation")
+                        || property == css_property!("animation-name")
+                    {
+                        value = prefix_css_animation_names(&value, prefix);
+                    }
+                }
+
+                if is_allowed_css_value(
+                    &value,
+                    self.config.allowed_css_values.get(&property),
+                    &|url| self.is_allowed_css_url(url),
+                ) {
+                    declaration.value = CssValue::parse(&value);
+                    Some(declaration)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Custom properties have no grammar, so their values only get the checks every value gets,
+    // like the ones on URLs.
+    fn sanitize_css_custom_property(
+        &self,
+        mut declaration: CssDeclaration,
+        custom_properties: &HashMap<String, CssValue>,
+    ) -> Option<CssDeclaration> {
+        if !self.is_allowed_css_custom_property(&declaration.property) {
+            return None;
+        }
+        if !css_var_references(&declaration.value).is_empty() {
+            declaration.value =
+                self.sanitize_css_var_references(&declaration.value, custom_properties)?;
+        }
+
+        if is_allowed_css_value(&declaration.value.to_css_string(), None, &|url| {
+            self.is_allowed_css_url(url)
+        }) {
+            Some(declaration)
+        } else {
+            None
+        }
+    }
+
+    fn sanitize_css_var_references(
+        &self,
+        value: &CssValue,
+        custom_properties: &HashMap<String, CssValue>,
+    ) -> Option<CssValue> {
+        match self.config.css_var_policy {
+            CssVarPolicy::Remove => None,
+            CssVarPolicy::Allow => {
+                if css_var_references(value)
+                    .iter()
+                    .all(|name| self.is_allowed_css_custom_property(name))
+                {
+                    Some(value.clone())
+                } else {
+                    None
+                }
+            }
+            CssVarPolicy::Resolve => resolve_css_var_references(value, custom_properties),
+        }
+    }
+
+    fn is_allowed_css_custom_property(&self, name: &str) -> bool {
+        self.config
+            .allowed_css_custom_property_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    }
+
+    // Collects the allowed custom properties declared in `declarations` for
+    // `CssVarPolicy::Resolve`. Later declarations win, as if they all applied to the same element.
+    fn collect_css_custom_properties(
+        &self,
+        declarations: &[CssDeclaration],
+        custom_properties: &mut HashMap<String, CssValue>,
+    ) {
+        if self.config.css_var_policy != CssVarPolicy::Resolve {
+            return;
+        }
+        for declaration in declarations {
+            if self.is_allowed_css_custom_property(&declaration.property) {
+                custom_properties.insert(declaration.property.clone(), declaration.value.clone());
+            }
+        }
+    }
+
+    fn collect_css_custom_properties_in_rules(
+        &self,
+        rules: &[CssRule],
+        custom_properties: &mut HashMap<String, CssValue>,
+    ) {
+        for rule in rules {
+            match rule {
+                CssRule::StyleRule(style_rule) => {
+                    self.collect_css_custom_properties(&style_rule.declarations, custom_properties)
+                }
+                CssRule::AtRule(at_rule) => {
+                    if let Some(CssAtRuleBlock::Rules(rules)) = &at_rule.block {
+                        self.collect_css_custom_properties_in_rules(rules, custom_properties)
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_allowed_css_url(&self, url: &str) -> bool {
+        is_allowed_url(url, &self.config.allowed_css_protocols)
+            && match self.config.allowed_css_hosts {
+                Some(ref allowed_hosts) => is_allowed_host(url, allowed_hosts),
+                None => true,
+            }
+    }
+
+    fn sanitize_style_tag_css(&'arena self, node: Ref<'arena>) {
+        if let NodeData::Element { ref name, .. } = node.data {
+            if name.ns == ns!(html) && name.local == local_name!("style") {
+                // TODO: is it okay to assume <style> tags will only ever have one text node child?
+                if let Some(first_child) = node.first_child.take() {
+                    if let NodeData::Text { ref contents, .. } = first_child.data {
+                        let source =
+                            strip_css_comments(&contents.borrow(), self.config.allow_css_comments);
+                        let rules = parse_css_stylesheet(&source);
+                        let mut custom_properties = HashMap::new();
+                        self.collect_css_custom_properties_in_rules(&rules, &mut custom_properties);
+                        let sanitized_rules = self.sanitize_css_rules(rules, &custom_properties);
+                        first_child.detach();
+                        let stylesheet = self.arena.alloc(Node::new(NodeData::StyleSheet {
+                            rules: sanitized_rules,
+                            source,
+                            serialization: self.config.css_serialization,
+                        }));
+                        node.append(stylesheet);
+                    }
+                }
+            }
+        }
+    }
+
+    fn sanitize_style_attribute_css(&self, node: Ref<'arena>) {
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            let mut i = 0;
+            let attrs = &mut attrs.borrow_mut();
+
+            while i != attrs.len() {
+                if let Attribute::Text(attr) = &attrs[i] {
+                    if attr.name.local == local_name!("style") {
+                        let source =
+                            strip_css_comments(&attr.value, self.config.allow_css_comments);
+                        let declarations = parse_css_style_attribute(&source);
+                        let mut custom_properties = HashMap::new();
+                        self.collect_css_custom_properties(&declarations, &mut custom_properties);
+                        let declarations =
+                            self.sanitize_css_declarations(declarations, &custom_properties);
+                        let name = attr.name.clone();
+                        attrs.remove(i);
+                        attrs.insert(
+                            i,
+                            Attribute::Style(StyleAttribute {
+                                name,
+                                value: declarations,
+                                source,
+                                serialization: self.config.css_serialization,
+                                serialized_value: None,
+                            }),
+                        );
+                    }
+                }
+                i += 1;
+            }
+        }
+    }
+
+    // Moves the sanitized rules in `<style>` elements into the `style` attributes of the elements
+    // they match, for clients like email readers that ignore stylesheets. Rules that can't be
+    // inlined, like at-rules and ones using `:hover`, stay in their `<style>` element, which is
+    // removed once it's empty. Elements that aren't allowed a `style` attribute are skipped.
+    fn inline_css(&'arena self, root: Ref<'arena>) {
+        let nodes: Vec<Ref<'arena>> = root.descendants().collect();
+        // Rules that match elements which can't have a style attribute are left in the stylesheet
+        // for them.
+        let (elements, unstyled_elements): (Vec<Ref<'arena>>, Vec<Ref<'arena>>) = nodes
+            .iter()
+            .copied()
+            .filter(|node| matches!(node.data, NodeData::Element { .. }))
+            .partition(|node| match node.data {
+                NodeData::Element { ref name, .. } => {
+                    self.is_allowed_attribute(name, &local_name!("style"))
+                }
+                _ => false,
+            });
+        let mut cascades: Vec<Vec<CascadedCssDeclaration>> = vec![vec![]; elements.len()];
+        let mut order = 0;
+
+        for stylesheet in nodes {
+            let (rules, source, serialization) = match stylesheet.data {
+                NodeData::StyleSheet {
+                    ref rules,
+                    ref source,
+                    serialization,
+                } => (rules, source, serialization),
+                _ => continue,
+            };
+
+            let mut remaining_rules = vec![];
+            for rule in rules {
+                let style_rule = match rule {
+                    CssRule::StyleRule(style_rule) => style_rule,
+                    CssRule::AtRule(_) => {
+                        remaining_rules.push(rule.clone());
+                        continue;
+                    }
+                };
+                let (selectors, mut remaining_selectors): (Vec<CssSelector>, Vec<CssSelector>) =
+                    parse_css_selectors(&style_rule.selectors)
+                        .into_iter()
+                        .partition(is_static_css_selector);
+
+                for selector in selectors {
+                    for (element, cascade) in elements.iter().zip(cascades.iter_mut()) {
+                        if matches_css_selector(&selector, element) {
+                            cascade.extend(style_rule.declarations.iter().map(|declaration| {
+                                CascadedCssDeclaration {
+                                    important: declaration.important,
+                                    inline: false,
+                                    specificity: selector.specificity(),
+                                    order,
+                                    declaration: declaration.clone(),
+                                }
+                            }));
+                        }
+                    }
+                    order += 1;
+                    if unstyled_elements
+                        .iter()
+                        .any(|element| matches_css_selector(&selector, element))
+                    {
+                        remaining_selectors.push(selector);
+                    }
+                }
+
+                if !remaining_selectors.is_empty() {
+                    remaining_rules.push(CssRule::StyleRule(CssStyleRule {
+                        selectors: remaining_selectors
+                            .iter()
+                            .map(|selector| selector.to_css_string())
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                        declarations: style_rule.declarations.clone(),
+                        span: style_rule.span,
+                    }));
+                }
+            }
+
+            if let Some(style) = stylesheet.parent.get() {
+                if remaining_rules.is_empty() {
+                    style.detach();
+                } else {
+                    stylesheet.detach();
+                    style.append(self.arena.alloc(Node::new(NodeData::StyleSheet {
+                        rules: remaining_rules,
+                        source: source.clone(),
+                        serialization,
+                    })));
+                }
+            }
+        }
+
+        for (element, cascade) in elements.into_iter().zip(cascades) {
+            if !cascade.is_empty() {
+                self.write_inline_css(element, cascade);
+            }
+        }
+    }
+
+    // Merges the declarations from the stylesheets in `cascade` with the ones already in
+    // `element`'s `style` attribute, keeping only the one that wins the cascade for each property.
+    fn write_inline_css(&self, element: Ref<'arena>, mut cascade: Vec<CascadedCssDeclaration>) {
+        if let NodeData::Element { ref attrs, .. } = element.data {
+            let attrs = &mut attrs.borrow_mut();
+            let style = attrs.iter().position(|attr| match attr {
+                Attribute::Style(attr) => attr.name.local == local_name!("style"),
+                Attribute::Text(attr) => attr.name.local == local_name!("style"),
+            });
+            if let Some(Attribute::Style(attr)) = style.map(|i| &attrs[i]) {
+                cascade.extend(attr.value.iter().enumerate().map(|(order, declaration)| {
+                    CascadedCssDeclaration {
+                        important: declaration.important,
+                        inline: true,
+                        specificity: 0,
+                        order,
+                        declaration: declaration.clone(),
+                    }
+                }));
+            }
+            cascade.sort_by_key(|cascaded| {
+                (
+                    cascaded.important,
+                    cascaded.inline,
+                    cascaded.specificity,
+                    cascaded.order,
+                )
+            });
+
+            let mut properties = HashSet::new();
+            let mut declarations: Vec<CssDeclaration> = cascade
+                .into_iter()
+                .rev()
+                .filter(|cascaded| properties.insert(cascaded.declaration.property.clone()))
+                .map(|cascaded| cascaded.declaration)
+                .collect();
+            declarations.reverse();
+
+            // Re-parse the merged declarations so their spans point into their new source.
+            let source = serialize_css_declarations(&declarations);
+            let inline_style = Attribute::Style(StyleAttribute {
+                name: QualName::new(None, ns!(), local_name!("style")),
+                value: parse_css_style_attribute(&source),
+                source,
+                serialization: self.config.css_serialization,
+                serialized_value: None,
+            });
+            match style {
+                Some(i) => attrs[i] = inline_style,
+                None => attrs.push(inline_style),
+            }
+        }
+    }
+
+    fn add_unwrapped_content_whitespace(
+        &self,
+        wrapping_node: Ref<'arena>,
+        unwrapped_node: Ref<'arena>,
+    ) {
+        if let NodeData::Element { ref name, .. } = wrapping_node.data {
+            if let Some(content_whitespace) = self
+                .config
+                .whitespace_around_unwrapped_content
+                .get(&name.local)
+            {
+                if let NodeData::Text { ref contents, .. } = unwrapped_node.data {
+                    contents.replace_with(|current| {
+                        format_tendril!(
+                            "{}{}{}",
+                            content_whitespace.before,
+                            current,
+                            content_whitespace.after
+                        )
+                    });
+                }
+            }
+        }
+    }
+}
+
+// A declaration from a stylesheet or `style` attribute, along with where it comes in the cascade.
+#[derive(Clone)]
+struct CascadedCssDeclaration {
+    important: bool,
+    inline: bool,
+    specificity: u32,
+    order: usize,
+    declaration: CssDeclaration,
+}
+
+fn is_same_node<'arena>(a: Option<Ref<'arena>>, b: Option<Ref<'arena>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => std::ptr::eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Elements that never have an end tag.
+fn is_void_element(name: &LocalName) -> bool {
+    matches!(
+        *name,
+        local_name!("area")
+            | local_name!("base")
+            | local_name!("br")
+            | local_name!("col")
+            | local_name!("embed")
+            | local_name!("hr")
+            | local_name!("img")
+            | local_name!("input")
+            | local_name!("link")
+            | local_name!("meta")
+            | local_name!("param")
+            | local_name!("source")
+            | local_name!("track")
+            | local_name!("wbr")
+    )
+}
+
+fn add_prefix(value: &str, prefix: &str) -> String {
+    if value.is_empty() || value.starts_with(prefix) {
+        value.to_string()
+    } else {
+        format!("{}{}", prefix, value)
+    }
+}
+
+fn add_prefix_to_tokens(value: &str, prefix: &str) -> String {
+    value
+        .split_ascii_whitespace()
+        .map(|token| add_prefix(token, prefix))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Prefixes the ID and class selectors in a serialized selector to match `prefix_attributes`.
+fn prefix_css_selector(
+    selector: &str,
+    id_prefix: Option<&str>,
+    class_prefix: Option<&str>,
+) -> String {
+    let mut parser_input = ParserInput::new(selector);
+    let mut parser = CssParser::new(&mut parser_input);
+    let mut prefixed = String::new();
+    let mut after_period = false;
+    serialize_tokens(&mut parser, &mut prefixed, &mut |token, _| {
+        let token = match (token, id_prefix, class_prefix) {
+            (Token::IDHash(id), Some(prefix), _) => Token::IDHash(add_prefix(&id, prefix).into()),
+            (Token::Ident(class), _, Some(prefix)) if after_period => {
+                Token::Ident(add_prefix(&class, prefix).into())
+            }
+            (token, _, _) => token,
+        };
+        after_period = token == Token::Delim('.');
+        Some(token)
+    });
+    prefixed
+}
+
+// Relative URLs are resolved against a placeholder base so that scheme-relative ones like
+// `//example.com/font.woff` are checked against the allowed hosts too.
+fn is_allowed_host(url: &str, allowed_hosts: &HashSet<&str>) -> bool {
+    let base = Url::parse("https://relative.invalid/").unwrap();
+    match base.join(url) {
+        Ok(url) => match url.host_str() {
+            Some(host) => host == "relative.invalid" || allowed_hosts.contains(host),
+            // URLs without a host, like `data:` URLs, don't fetch anything.
+            None => true,
+        },
+        Err(_) => false,
+    }
+}
+
+// Prefixes the keyframes names in an `animation` or `animation-name` value to match
+// `animation_name_prefix`. Every identifier that isn't one of the other `animation` keywords is
+// taken to be a name.
+fn prefix_css_animation_names(value: &str, prefix: &str) -> String {
+    let mut parser_input = ParserInput::new(value);
+    let mut parser = CssParser::new(&mut parser_input);
+    let mut prefixed = String::new();
+    serialize_tokens(&mut parser, &mut prefixed, &mut |token, depth| {
+        Some(match token {
+            Token::Ident(name) if depth == 0 && !is_animation_keyword(&name) => {
+                Token::Ident(add_prefix(&name, prefix).into())
+            }
+            Token::QuotedString(name) if depth == 0 => {
+                Token::QuotedString(add_prefix(&name, prefix).into())
+            }
+            token => token,
+        })
+    });
+    prefixed
+}
+
+fn is_animation_keyword(ident: &str) -> bool {
+    [
+        "alternate",
+        "alternate-reverse",
+        "backwards",
+        "both",
+        "ease",
+        "ease-in",
+        "ease-in-out",
+        "ease-out",
+        "forwards",
+        "infinite",
+        "inherit",
+        "initial",
+        "linear",
+        "none",
+        "normal",
+        "paused",
+        "revert",
+        "reverse",
+        "running",
+        "step-end",
+        "step-start",
+        "unset",
+    ]
+    .iter()
+    .any(|keyword| keyword.eq_ignore_ascii_case(ident))
+}
+
+fn is_allowed_url(url: &str, allowed_protocols: &HashSet<Protocol>) -> bool {
+    match Url::parse(url) {
+        Ok(url) => allowed_protocols.contains(&Protocol::Scheme(url.scheme())),
+        Err(ParseError::RelativeUrlWithoutBase) => allowed_protocols.contains(&Protocol::Relative),
+        Err(_) => false,
+    }
+}
+
+// Mutation XSS payloads rely on an element switching namespaces when the sanitized output is
+// parsed again (e.g. an HTML `<mglyph>` under `<mtext>` becomes a MathML element, turning a
+// following `<style>` from raw text into markup). Only let an element stay where the parser could
+// have put it. Adapted from DOMPurify's `_checkValidNamespace`.
+// Animating an href with `<animate>` or `<set>` would sidestep the protocol checks, so an animation
+// that targets one is removed along with whatever values it would set.
+fn animates_href(node: Ref) -> bool {
+    match node.data {
+        NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } if name.ns == ns!(svg) => attrs.borrow().iter().any(|attr| match attr {
+            Attribute::Text(attr) => {
+                attr.name.local == local_name!("attributeName")
+                    && attr.value.trim().to_ascii_lowercase().ends_with("href")
+            }
+            Attribute::Style(_) => false,
+        }),
+        _ => false,
+    }
+}
+
+fn has_valid_namespace(node: Ref) -> bool {
+    let name = match node.data {
+        NodeData::Element { ref name, .. } => name,
+        _ => return true,
+    };
+    // The root of the tree is treated as if it were inside an HTML `<body>`.
+    let (parent_name, parent_is_annotation_xml_integration_point) = match node.parent.get() {
+        Some(&Node {
+            data:
+                NodeData::Element {
+                    ref name,
+                    mathml_annotation_xml_integration_point,
+                    ..
+                },
+            ..
+        }) => (name.clone(), mathml_annotation_xml_integration_point),
+        _ => (QualName::new(None, ns!(html), local_name!("body")), false),
+    };
+
+    match name.ns {
+        ns!(svg) => match parent_name.ns {
+            ns!(html) => name.local == local_name!("svg"),
+            ns!(mathml) => {
+                name.local == local_name!("svg")
+                    && (parent_name.local == local_name!("annotation-xml")
+                        || is_mathml_text_integration_point(&parent_name.local))
+            }
+            _ => true,
+        },
+        ns!(mathml) => match parent_name.ns {
+            ns!(html) => name.local == local_name!("math"),
+            ns!(svg) => {
+                name.local == local_name!("math")
+                    && is_svg_html_integration_point(&parent_name.local)
+            }
+            _ => true,
+        },
+        ns!(html) => {
+            let allowed_in_parent = match parent_name.ns {
+                ns!(svg) => is_svg_html_integration_point(&parent_name.local),
+                ns!(mathml) => {
+                    is_mathml_text_integration_point(&parent_name.local)
+                        || parent_is_annotation_xml_integration_point
+                }
+                _ => true,
+            };
+            allowed_in_parent
+                && !is_mathml_element_name(&name.local)
+                && (is_common_svg_and_html_element_name(&name.local)
+                    || !is_svg_element_name(&name.local))
+        }
+        _ => false,
+    }
+}
+
+fn is_svg_html_integration_point(name: &LocalName) -> bool {
+    matches!(
+        *name,
+        local_name!("foreignObject") | local_name!("desc") | local_name!("title")
+    )
+}
+
+fn is_mathml_text_integration_point(name: &LocalName) -> bool {
+    matches!(
+        *name,
+        local_name!("mi")
+            | local_name!("mo")
+            | local_name!("mn")
+            | local_name!("ms")
+            | local_name!("mtext")
+    )
+}
+
+fn is_common_svg_and_html_element_name(name: &LocalName) -> bool {
+    matches!(
+        *name,
+        local_name!("a")
+            | local_name!("font")
+            | local_name!("script")
+            | local_name!("style")
+            | local_name!("title")
+    )
+}
+
+fn is_svg_element_name(name: &LocalName) -> bool {
+    matches!(
+        *name,
+        local_name!("a")
+            | local_name!("altGlyph")
+            | local_name!("altGlyphDef")
+            | local_name!("altGlyphItem")
+            | local_name!("animate")
+            | local_name!("animateColor")
+            | local_name!("animateMotion")
+            | local_name!("animateTransform")
+            | local_name!("circle")
+            | local_name!("clipPath")
+            | local_name!("cursor")
+            | local_name!("defs")
+            | local_name!("desc")
+            | local_name!("ellipse")
+            | local_name!("feBlend")
+            | local_name!("feImage")
+            | local_name!("filter")
+            | local_name!("font")
+            | local_name!("foreignObject")
+            | local_name!("g")
+            | local_name!("glyph")
+            | local_name!("glyphRef")
+            | local_name!("hkern")
+            | local_name!("image")
+            | local_name!("line")
+            | local_name!("linearGradient")
+            | local_name!("marker")
+            | local_name!("mask")
+            | local_name!("metadata")
+            | local_name!("mpath")
+            | local_name!("path")
+            | local_name!("pattern")
+            | local_name!("polygon")
+            | local_name!("polyline")
+            | local_name!("radialGradient")
+            | local_name!("rect")
+            | local_name!("script")
+            | local_name!("set")
+            | local_name!("stop")
+            | local_name!("style")
+            | local_name!("svg")
+            | local_name!("switch")
+            | local_name!("symbol")
+            | local_name!("text")
+            | local_name!("textPath")
+            | local_name!("title")
+            | local_name!("tref")
+            | local_name!("tspan")
+            | local_name!("use")
+            | local_name!("view")
+            | local_name!("vkern")
+    )
+}
+
+fn is_mathml_element_name(name: &LocalName) -> bool {
+    matches!(
+        *name,
+        local_name!("annotation")
+            | local_name!("annotation-xml")
+            | local_name!("maction")
+            | local_name!("maligngroup")
+            | local_name!("malignmark")
+            | local_name!("math")
+            | local_name!("menclose")
+            | local_name!("merror")
+            | local_name!("mfenced")
+            | local_name!("mfrac")
+            | local_name!("mglyph")
+            | local_name!("mi")
+            | local_name!("mlabeledtr")
+            | local_name!("mmultiscripts")
+            | local_name!("mn")
+            | local_name!("mo")
+            | local_name!("mover")
+            | local_name!("mpadded")
+            | local_name!("mphantom")
+            | local_name!("mprescripts")
+            | local_name!("mroot")
+            | local_name!("mrow")
+            | local_name!("ms")
+            | local_name!("mspace")
+            | local_name!("msqrt")
+            | local_name!("mstyle")
+            | local_name!("msub")
+            | local_name!("msubsup")
+            | local_name!("msup")
+            | local_name!("mtable")
+            | local_name!("mtd")
+            | local_name!("mtext")
+            | local_name!("mtr")
+            | local_name!("munder")
+            | local_name!("munderover")
+            | local_name!("none")
+            | local_name!("semantics")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arena_dom::{create_element, parse_html};
+    use crate::config::basic::BASIC_CONFIG;
+    use crate::config::mathml::MATHML_CONFIG;
+    use crate::config::svg::SVG_CONFIG;
+
+    use std::str;
+
+    struct MockRead {
+        contents: &'static str,
     }
 
     impl MockRead {
@@ -431,17 +1749,45 @@ mod test {
             allow_comments: false,
             allow_doctype: false,
             allowed_elements: HashSet::new(),
+            rename_elements: HashMap::new(),
             allowed_attributes: HashSet::new(),
             allowed_attributes_per_element: HashMap::new(),
             add_attributes: HashMap::new(),
             add_attributes_per_element: HashMap::new(),
             allowed_protocols: HashMap::new(),
+            allowed_svg_elements: HashSet::new(),
+            allowed_svg_attributes: HashSet::new(),
+            allowed_svg_protocols: HashMap::new(),
+            allowed_mathml_elements: HashSet::new(),
+            allowed_mathml_attributes: HashSet::new(),
             allowed_css_at_rules: HashSet::new(),
             allowed_css_properties: HashSet::new(),
+            allowed_css_custom_property_prefixes: HashSet::new(),
+            css_var_policy: CssVarPolicy::Remove,
+            allowed_css_values: HashMap::new(),
+            rewrite_css_values: vec![],
+            rewrite_css_values_per_property: HashMap::new(),
             allowed_css_protocols: HashSet::new(),
+            allowed_css_hosts: None,
+            allowed_css_media_types: HashSet::new(),
+            allowed_css_media_features: HashSet::new(),
             allow_css_comments: false,
+            allowed_css_selector_attributes: HashSet::new(),
+            allow_css_universal_selector: false,
+            allow_css_root_selectors: false,
+            css_selector_scope: None,
+            id_prefix: None,
+            class_prefix: None,
+            animation_name_prefix: None,
+            css_serialization: CssSerialization::Normalized,
+            inline_css: false,
+            disallowed_element_disposition: ElementDisposition::Unwrap,
+            disallowed_element_disposition_per_element: HashMap::new(),
             remove_contents_when_unwrapped: HashSet::new(),
             whitespace_around_unwrapped_content: HashMap::new(),
+            remove_empty_elements: HashSet::new(),
+            remove_whitespace_only_elements: false,
+            round_trip_check: false,
         };
     }
 
@@ -632,7 +1978,7 @@ mod test {
         assert_eq!(
             str::from_utf8(&output).unwrap(),
             "<html><a href=\"url\"></a>\
-                <img src=\"url\"></img>\
+                <img src=\"url\">\
                 <span></span></html>"
         );
     }
@@ -665,7 +2011,7 @@ mod test {
         assert_eq!(
             str::from_utf8(&output).unwrap(),
             "<html><a href=\"url\"></a>\
-                <img src=\"url\"></img>\
+                <img src=\"url\">\
                 <span></span></html>"
         );
     }
@@ -715,7 +2061,7 @@ mod test {
             .unwrap();
         assert_eq!(
             str::from_utf8(&output).unwrap(),
-            "<html><a href=\"url1\"><img src=\"url2\"></img></a></html>"
+            "<html><a href=\"url1\"><img src=\"url2\"></a></html>"
         );
     }
 
@@ -766,9 +2112,9 @@ mod test {
             "<html><a></a>\
             <a href=\"https://example.com\"></a>\
             <a></a>\
-            <img src=\"/relative\"></img>\
-            <img src=\"https://example.com\"></img>\
-            <img src=\"http://example.com\"></img></html>"
+            <img src=\"/relative\">\
+            <img src=\"https://example.com\">\
+            <img src=\"http://example.com\"></html>"
         );
     }
 
@@ -843,31 +2189,1390 @@ mod test {
             .unwrap();
         assert_eq!(
             str::from_utf8(&output).unwrap(),
-            "<html><style>div { background-image: url(https://example.com) }</style></html>"
+            "<html><style>div { background-image: url(https://example.com); }</style></html>"
         );
     }
 
     #[test]
-    fn remove_doctype() {
-        let mut disallow_doctype_config = EMPTY_CONFIG.clone();
-        disallow_doctype_config.allow_doctype = false;
-        disallow_doctype_config
+    fn sanitize_css_values() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
             .allowed_elements
-            .extend(vec![local_name!("html"), local_name!("div")]);
-        let sanitizer = Sanitizer::new(&disallow_doctype_config, vec![]);
-        let mut mock_data = MockRead::new("<!DOCTYPE html><div></div>");
+            .extend(vec![local_name!("div")]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config.allowed_css_properties.extend(vec![
+            css_property!("background-image"),
+            css_property!("color"),
+            css_property!("position"),
+            css_property!("width"),
+            css_property!("z-index"),
+        ]);
+        sanitize_css_config.allowed_css_values.extend(vec![
+            (
+                css_property!("position"),
+                hashset! { CssValueType::Keyword("relative") },
+            ),
+            (
+                css_property!("width"),
+                hashset! {
+                    CssValueType::Length,
+                    CssValueType::Percentage,
+                    CssValueType::Function("calc"),
+                },
+            ),
+            (css_property!("z-index"), hashset! { CssValueType::Integer }),
+        ]);
+        sanitize_css_config
+            .allowed_css_protocols
+            .extend(vec![Protocol::Scheme("https")]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<div style=\"position: fixed; position: relative; z-index: 1.5; z-index: 10; \
+             width: expression(alert(1)); width: calc(100% - 10px) !important; \
+             color: rgb(0, 0, 0); color: EXPRESSION(alert(1)); \
+             background-image: url(javascript:alert(1)); \
+             background-image: image-set('javascript:alert(1)' 1x)\"></div>",
+        );
         let mut output = vec![];
         sanitizer
-            .sanitize_document(&mut mock_data, &mut output)
+            .sanitize_fragment(&mut mock_data, &mut output)
             .unwrap();
-        assert_eq!(str::from_utf8(&output).unwrap(), "<html><div></div></html>");
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<div style=\"position: relative; z-index: 10; width: calc(100% - 10px) !important; \
+             color: rgb(0, 0, 0);\"></div>"
+        );
     }
 
     #[test]
-    fn allow_doctype() {
-        let mut allow_doctype_config = EMPTY_CONFIG.clone();
-        allow_doctype_config.allow_doctype = true;
-        allow_doctype_config
+    fn sanitize_nested_css_rules() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("media")]);
+        sanitize_css_config
+            .allowed_css_media_features
+            .extend(vec!["min-width"]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color")]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>@media (min-width: 100px) { div { color: red; color: expression(alert(1)); \
+             margin: 10px; } }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@media (min-width: 100px) { div { color: red; } }</style></html>"
+        );
+    }
+
+    #[test]
+    fn rewrite_css_values() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config.allowed_css_properties.extend(vec![
+            css_property!("color"),
+            css_property!("font-size"),
+            css_property!("height"),
+            css_property!("position"),
+            css_property!("width"),
+            css_property!("z-index"),
+        ]);
+        sanitize_css_config
+            .rewrite_css_values
+            .push(CssValueRewrite::StripImportant);
+        sanitize_css_config
+            .rewrite_css_values_per_property
+            .extend(vec![
+                (
+                    css_property!("font-size"),
+                    vec![CssValueRewrite::ClampDimension {
+                        unit: "px",
+                        max: 32.,
+                    }],
+                ),
+                (
+                    css_property!("height"),
+                    vec![
+                        CssValueRewrite::ConvertUnit {
+                            from: "vh",
+                            to: "px",
+                            factor: 6.,
+                        },
+                        CssValueRewrite::ClampDimension {
+                            unit: "px",
+                            max: 400.,
+                        },
+                    ],
+                ),
+                (
+                    css_property!("position"),
+                    vec![
+                        CssValueRewrite::ReplaceKeyword {
+                            from: "fixed",
+                            to: "static",
+                        },
+                        CssValueRewrite::ReplaceKeyword {
+                            from: "sticky",
+                            to: "static",
+                        },
+                    ],
+                ),
+                (
+                    css_property!("width"),
+                    vec![
+                        CssValueRewrite::ConvertUnit {
+                            from: "vw",
+                            to: "%",
+                            factor: 1.,
+                        },
+                        CssValueRewrite::ClampDimension {
+                            unit: "%",
+                            max: 100.,
+                        },
+                    ],
+                ),
+                (
+                    css_property!("z-index"),
+                    vec![CssValueRewrite::ClampInteger { min: 0, max: 100 }],
+                ),
+            ]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>div { position: fixed; z-index: 2147483647; color: red !important; }\
+             p { font-size: calc(1px * 100000); height: -1000px; width: -150%; z-index: +500 }\
+             p { height: max(10px, 5000px); z-index: -5; width: 50%; }</style>\
+             <div style=\"font-size: 100px; height: 50vh; width: 150vw; position: relative; \
+             z-index: 10 ! important\"></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div { position: static; z-index: 100; color: red; }\
+             p { height: -400px; width: -100%; z-index: +100; }\
+             p { z-index: 0; width: 50%; }</style>\
+             <div style=\"font-size: 32px; height: 300px; width: 100%; position: relative; \
+             z-index: 10;\"></div></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_css_selectors() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color")]);
+        sanitize_css_config
+            .allowed_css_selector_attributes
+            .extend(vec![local_name!("title")]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>* { color: red; } body, .content { color: blue; } \
+             input[value^=a] { color: red; } a[title] { color: blue; } \
+             div:has(img) { color: red; } p:hover, p:not([VALUE]), :root { color: green; }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>.content { color: blue; }a[title] { color: blue; }\
+             p:hover { color: green; }</style></html>"
+        );
+    }
+
+    #[test]
+    fn scope_css_selectors() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("keyframes"), css_at_rule!("media")]);
+        sanitize_css_config
+            .allowed_css_media_types
+            .extend(vec!["print"]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color")]);
+        sanitize_css_config.css_selector_scope = Some(".user-content-123".to_string());
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>p, .note > a::before { color: red; } \
+             @media print { h1 { color: black; } } \
+             @keyframes fade { from { color: red; } 50% { color: blue; } }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>.user-content-123 p, .user-content-123 .note > a::before { color: red; }\
+             @media print { .user-content-123 h1 { color: black; } }\
+             @keyframes fade { from { color: red; }50% { color: blue; } }</style></html>"
+        );
+    }
+
+    #[test]
+    fn prefix_ids_and_classes() {
+        let mut prefix_config = EMPTY_CONFIG.clone();
+        prefix_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("a"),
+            local_name!("div"),
+            local_name!("label"),
+            local_name!("style"),
+        ]);
+        prefix_config.allowed_attributes.extend(vec![
+            local_name!("class"),
+            local_name!("for"),
+            local_name!("href"),
+            local_name!("id"),
+            local_name!("name"),
+        ]);
+        prefix_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color")]);
+        prefix_config.allowed_css_selector_attributes.extend(vec![
+            local_name!("class"),
+            local_name!("href"),
+            local_name!("id"),
+        ]);
+        prefix_config.id_prefix = Some("user-content-".to_string());
+        prefix_config.class_prefix = Some("uc-".to_string());
+        prefix_config.round_trip_check = true;
+        let sanitizer = Sanitizer::new(&prefix_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>#config, .note a[href] { color: red; } [id=\"config\"] { color: blue; } \
+             div[class~=note] { color: blue; } a[href=\"#config\"] { color: blue; }</style>\
+             <div id=\"config\" class=\"note  big\">\
+             <a name=\"location\" href=\"#config\">x</a>\
+             <a href=\"#user-content-config\">y</a>\
+             <a href=\"https://example.com/#config\">z</a>\
+             <label for=\"config\">label</label></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>#user-content-config, .uc-note a[href] { color: red; }</style>\
+             <div id=\"user-content-config\" class=\"uc-note uc-big\">\
+             <a name=\"user-content-location\" href=\"#user-content-config\">x</a>\
+             <a href=\"#user-content-config\">y</a>\
+             <a href=\"https://example.com/#config\">z</a>\
+             <label for=\"user-content-config\">label</label></div></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_css_import_and_font_face_urls() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("font-face"), css_at_rule!("import")]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("font-family"), css_property!("src")]);
+        sanitize_css_config
+            .allowed_css_protocols
+            .extend(vec![Protocol::Scheme("https"), Protocol::Relative]);
+        sanitize_css_config.allowed_css_hosts = Some(hashset! { "fonts.example.com" });
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>@import url(https://evil.example.com/a.css); \
+             @import \"https://fonts.example.com/b.css\" screen; \
+             @import \"javascript:alert(1)\"; @import \"c.css\"; \
+             @font-face { font-family: A; \
+             src: url(https://fonts.example.com/a.woff2) format(\"woff2\"), local(A); } \
+             @font-face { font-family: B; src: url(//evil.example.com/b.woff); } \
+             @font-face { font-family: C; src: url(\"http://fonts.example.com/c.woff\"); }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@import \"https://fonts.example.com/b.css\" screen; @import \"c.css\"; \
+             @font-face { font-family: A; \
+             src: url(https://fonts.example.com/a.woff2) format(\"woff2\"), local(A); }\
+             @font-face { font-family: B; }@font-face { font-family: C; }</style></html>"
+        );
+    }
+
+    #[test]
+    fn remove_css_imports_but_keep_font_faces() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("font-face")]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("font-family"), css_property!("src")]);
+        sanitize_css_config
+            .allowed_css_protocols
+            .extend(vec![Protocol::Scheme("https")]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>@import url(https://example.com/a.css); \
+             @font-face { font-family: A; src: url(https://example.com/a.woff2); }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@font-face { font-family: A; src: url(https://example.com/a.woff2); }\
+             </style></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_css_media_and_supports_conditions() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("media"), css_at_rule!("supports")]);
+        sanitize_css_config
+            .allowed_css_media_types
+            .extend(vec!["screen"]);
+        sanitize_css_config
+            .allowed_css_media_features
+            .extend(vec!["max-width", "prefers-color-scheme"]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color"), css_property!("display")]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>@media screen and (max-width: 600px), print { p { color: red; } } \
+             @media (prefers-color-scheme: dark) and (not (max-width: 10px)) { p { color: red; } } \
+             @media print { p { color: red; } } \
+             @media (min-resolution: 2dppx) { p { color: red; } } \
+             @supports (display: grid) and (not (color: red)) { p { color: red; } } \
+             @supports (position: fixed) { p { color: red; } } \
+             @supports selector(p > a) { p { color: red; } } \
+             @supports selector(p:has(a)) { p { color: red; } }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@media screen and (max-width: 600px) { p { color: red; } }\
+             @media (prefers-color-scheme: dark) and (not (max-width: 10px)) { p { color: red; } }\
+             @supports (display: grid) and (not (color: red)) { p { color: red; } }\
+             @supports selector(p > a) { p { color: red; } }</style></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_css_keyframes_and_animations() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config.allowed_css_at_rules.extend(vec![
+            css_at_rule!("keyframes"),
+            css_at_rule!("-webkit-keyframes"),
+        ]);
+        sanitize_css_config.allowed_css_properties.extend(vec![
+            css_property!("animation"),
+            css_property!("animation-iteration-count"),
+            css_property!("animation-name"),
+            css_property!("opacity"),
+        ]);
+        sanitize_css_config
+            .rewrite_css_values_per_property
+            .extend(vec![
+                (
+                    css_property!("animation"),
+                    vec![
+                        CssValueRewrite::ClampTime { max: 5. },
+                        CssValueRewrite::ClampIterationCount { max: 3. },
+                    ],
+                ),
+                (
+                    css_property!("animation-iteration-count"),
+                    vec![CssValueRewrite::ClampIterationCount { max: 3. }],
+                ),
+            ]);
+        sanitize_css_config.animation_name_prefix = Some("user-content-".to_string());
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>@keyframes fade { FROM, 50% { opacity: 0; position: fixed; } \
+             to { opacity: 1; } div { opacity: 1; } 150% { opacity: 1; } } \
+             @-webkit-keyframes \"spin\" { to { opacity: 1; } }</style>\
+             <div style=\"animation: fade 60s steps(4, end) infinite alternate; \
+             animation-name: fade, none; animation-iteration-count: 1000\"></div>\
+             <div style=\"animation: fade calc(5s * 1000); animation-iteration-count: calc(1000)\">\
+             </div><div style=\"animation-iteration-count: calc(infinite); opacity: 1\"></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@keyframes user-content-fade { from, 50% { opacity: 0; }\
+             to { opacity: 1; } }@-webkit-keyframes \"user-content-spin\" { to { opacity: 1; } }\
+             </style><div style=\"animation: user-content-fade 5s steps(4, end) 3 alternate; \
+             animation-name: user-content-fade, none; animation-iteration-count: 3;\"></div>\
+             <div style=\"\"></div><div style=\"opacity: 1;\"></div></html>"
+        );
+    }
+
+    #[test]
+    fn preserve_css_formatting() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("media")]);
+        sanitize_css_config
+            .allowed_css_media_types
+            .extend(vec!["print"]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color"), css_property!("margin")]);
+        sanitize_css_config.allow_css_comments = true;
+        sanitize_css_config.css_serialization = CssSerialization::Preserved;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>\n/* Theme */\nh1 {\n  color: red;\n  position: fixed;\n}\n\n\
+             @media print {\n  p { color:blue }\n  div>p { color: green;  }\n}\n\
+             @media screen { p { color: red } }\n\
+             } a:hover { color: red }\n\
+             .bad {\n  behavior: url(x.htc)\n}\n</style>\
+             <div style=\"color:  red;  position: fixed ;margin:0 \"></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>\n/* Theme */\nh1 {\n  color: red;\n}\n\n\
+             @media print {\n  p { color:blue }\n  div > p { color: green;  }\n}\n\
+             .bad {}\n</style><div style=\"color:  red;margin:0 \"></div></html>"
+        );
+    }
+
+    #[test]
+    fn preserve_css_formatting_unterminated_blocks() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("media"), css_at_rule!("keyframes")]);
+        sanitize_css_config
+            .allowed_css_media_types
+            .extend(vec!["screen"]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color")]);
+        sanitize_css_config.css_serialization = CssSerialization::Preserved;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+
+        let mut mock_data = MockRead::new("<style>p { color: red; position: fixed");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>p { color: red;</style></html>"
+        );
+
+        let mut mock_data = MockRead::new("<style>@media screen { p { color: red }");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@media screen { p { color: red }</style></html>"
+        );
+
+        let mut mock_data =
+            MockRead::new("<style>@keyframes fade { from { color: red; position: fixed }");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>@keyframes fade { from { color: red;}</style></html>"
+        );
+    }
+
+    #[test]
+    fn minify_css() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_at_rules
+            .extend(vec![css_at_rule!("media"), css_at_rule!("keyframes")]);
+        sanitize_css_config
+            .allowed_css_media_types
+            .extend(vec!["print"]);
+        sanitize_css_config
+            .allowed_css_media_features
+            .extend(vec!["min-width"]);
+        sanitize_css_config.allowed_css_properties.extend(vec![
+            css_property!("color"),
+            css_property!("margin"),
+            css_property!("opacity"),
+            css_property!("width"),
+        ]);
+        sanitize_css_config.css_serialization = CssSerialization::Minified;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>\n/* Theme */\nh1 , h2 > a {\n  color: rgb( 1, 2 , 3 ) !important;\n}\n\
+             @media print, (min-width: 100px) {\n  p { margin: 0 /* none */ auto; }\n}\n\
+             @keyframes fade { from , 50% { opacity: 0 } }\n</style>\
+             <div style=\"color:  red;  width: calc( 100% - 2px ) ;margin:0 \"></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>h1,h2>a{color:rgb(1,2,3)!important}\
+             @media print,(min-width:100px){p{margin:0 auto}}@keyframes fade{from,50%{opacity:0}}\
+             </style><div style=\"color:red;width:calc(100% - 2px);margin:0\"></div></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_css_comments_and_hacks() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config.allowed_css_properties.extend(vec![
+            css_property!("color"),
+            css_property!("filter"),
+            css_property!("height"),
+            css_property!("margin"),
+            css_property!("width"),
+        ]);
+        let css = "<style><!--\np { color: red; /* note */ }\n/* x */ h1 { color: blue\\9; \
+                   margin: 0 !ie; width: 10px\\0/; *zoom: 1; _height: 1px; \
+                   filter: progid:DXImageTransform.Microsoft.gradient(); height: 1px }\n--></style>\
+                   <div style=\"color: red /* note */; margin: 0\"></div>";
+
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(css);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>p { color: red; }h1 { height: 1px ; }</style>\
+             <div style=\"color: red ; margin: 0;\"></div></html>"
+        );
+
+        sanitize_css_config.allow_css_comments = true;
+        sanitize_css_config.css_serialization = CssSerialization::Preserved;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(css);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>\np { color: red; /* note */ }\n/* x */ h1 { height: 1px }\n</style>\
+             <div style=\"color: red /* note */; margin: 0\"></div></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_css_custom_properties() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config.allowed_css_properties.extend(vec![
+            css_property!("color"),
+            css_property!("margin"),
+            css_property!("position"),
+        ]);
+        sanitize_css_config
+            .allowed_css_custom_property_prefixes
+            .extend(vec!["--theme-"]);
+        sanitize_css_config
+            .allowed_css_protocols
+            .extend(vec![Protocol::Scheme("https")]);
+        sanitize_css_config.rewrite_css_values_per_property.insert(
+            css_property!("position"),
+            vec![CssValueRewrite::ReplaceKeyword {
+                from: "fixed",
+                to: "static",
+            }],
+        );
+        let css = "<style>div { --theme-color: red; --theme-bg: url(javascript:alert(1)); \
+                   --other: blue; --theme-pos: fixed; }\n\
+                   p { color: var(--theme-color); margin: var(--theme-gap, 2px); \
+                   position: var(--theme-pos); }</style>\
+                   <div style=\"--theme-color: green; color: var(--theme-color); \
+                   margin: var(--other)\"></div>";
+
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(css);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div { --theme-color: red; --theme-pos: fixed; }p {  }</style>\
+             <div style=\"--theme-color: green;\"></div></html>"
+        );
+
+        sanitize_css_config.css_var_policy = CssVarPolicy::Allow;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(css);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div { --theme-color: red; --theme-pos: fixed; }\
+             p { color: var(--theme-color); margin: var(--theme-gap, 2px); }</style>\
+             <div style=\"--theme-color: green; color: var(--theme-color);\"></div></html>"
+        );
+
+        sanitize_css_config.css_var_policy = CssVarPolicy::Resolve;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(css);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div { --theme-color: red; --theme-pos: fixed; }\
+             p { color: red; margin: 2px; position: static; }</style>\
+             <div style=\"--theme-color: green; color: green;\"></div></html>"
+        );
+    }
+
+    #[test]
+    fn limit_css_var_expansion() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("div")]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("margin"), css_property!("padding")]);
+        sanitize_css_config
+            .allowed_css_custom_property_prefixes
+            .extend(vec!["--v"]);
+        sanitize_css_config.css_var_policy = CssVarPolicy::Resolve;
+        // Every level references the one below it twelve times over.
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<div style=\"--v0: 1px; \
+             --v1: var(--v0) var(--v0) var(--v0) var(--v0) var(--v0) var(--v0) \
+             var(--v0) var(--v0) var(--v0) var(--v0) var(--v0) var(--v0); \
+             --v2: var(--v1) var(--v1) var(--v1) var(--v1) var(--v1) var(--v1) \
+             var(--v1) var(--v1) var(--v1) var(--v1) var(--v1) var(--v1); \
+             --v3: var(--v2) var(--v2) var(--v2) var(--v2) var(--v2) var(--v2) \
+             var(--v2) var(--v2) var(--v2) var(--v2) var(--v2) var(--v2); \
+             padding: var(--v1); margin: var(--v3)\"></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        // `--v3` and `margin` would be 1728 values long.
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            format!(
+                "<html><div style=\"--v0: 1px; --v1: {}; --v2: {}; padding: {};\"></div></html>",
+                ["1px"; 12].join(" "),
+                ["1px"; 144].join(" "),
+                ["1px"; 12].join(" ")
+            )
+        );
+    }
+
+    #[test]
+    fn escape_css_custom_property_names() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("style"),
+        ]);
+        sanitize_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_custom_property_prefixes
+            .extend(vec!["--x", "--y"]);
+        let html = "<style>div { --x\\7d body\\7b background\\3a url\\28 javascript\\3a \
+                    alert\\28 1\\29\\29\\3b --y: 1 }</style>\
+                    <div style=\"--x\\3b position\\3a fixed\\3b --y: 1\"></div>";
+
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(html);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div { \
+             --x\\}body\\{background\\:url\\(javascript\\:alert\\(1\\)\\)\\;--y: 1 ; }</style>\
+             <div style=\"--x\\;position\\:fixed\\;--y: 1;\"></div></html>"
+        );
+
+        sanitize_css_config.css_serialization = CssSerialization::Minified;
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(html);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div{\
+             --x\\}body\\{background\\:url\\(javascript\\:alert\\(1\\)\\)\\;--y:1}</style>\
+             <div style=\"--x\\;position\\:fixed\\;--y:1\"></div></html>"
+        );
+    }
+
+    #[test]
+    fn inline_css() {
+        let mut inline_css_config = EMPTY_CONFIG.clone();
+        inline_css_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("a"),
+            local_name!("p"),
+            local_name!("span"),
+            local_name!("style"),
+        ]);
+        inline_css_config
+            .allowed_attributes
+            .extend(vec![local_name!("class"), local_name!("id")]);
+        inline_css_config
+            .allowed_attributes_per_element
+            .insert(local_name!("p"), hashset! { local_name!("style") });
+        inline_css_config
+            .allowed_css_at_rules
+            .insert(css_at_rule!("media"));
+        inline_css_config.allowed_css_media_types.insert("screen");
+        inline_css_config.allowed_css_properties.extend(vec![
+            css_property!("color"),
+            css_property!("margin"),
+            css_property!("padding"),
+        ]);
+        inline_css_config.inline_css = true;
+        let sanitizer = Sanitizer::new(&inline_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>p { color: red; margin: 0; } #intro { color: blue; } \
+             .note { color: green; padding: 1px !important; } span { color: red; }</style>\
+             <style>p, a:hover { margin: 1px; } @media screen { p { color: black; } }</style>\
+             <p id=\"intro\" class=\"note\" style=\"padding: 2px; margin: 2px\">one</p>\
+             <p>two <span>three</span></p>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>span { color: red; }</style>\
+             <style>a:hover { margin: 1px; }@media screen { p { color: black; } }</style>\
+             <p id=\"intro\" class=\"note\" style=\"color: blue; margin: 2px; \
+             padding: 1px !important;\">one</p><p style=\"color: red; margin: 1px;\">two \
+             <span>three</span></p></html>"
+        );
+    }
+
+    fn remove_lead_links<'arena>(node: Ref<'arena>, _: Arena<'arena>) {
+        if let NodeData::Document = node.data {
+            let links: Vec<Ref> = node.select("article > p.lead a[href]").collect();
+            for link in links {
+                link.detach();
+            }
+        }
+    }
+
+    fn empty_notes<'arena>(node: Ref<'arena>, _: Arena<'arena>) {
+        if node.matches("p.note, div:first-child") {
+            while let Some(child) = node.first_child.get() {
+                child.detach();
+            }
+        }
+    }
+
+    #[test]
+    fn select_nodes() {
+        let mut select_config = EMPTY_CONFIG.clone();
+        select_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("a"),
+            local_name!("article"),
+            local_name!("div"),
+            local_name!("p"),
+        ]);
+        select_config
+            .allowed_attributes
+            .extend(vec![local_name!("class"), local_name!("href")]);
+        let sanitizer = Sanitizer::new(&select_config, vec![&remove_lead_links, &empty_notes]);
+        let mut mock_data = MockRead::new(
+            "<div>first</div><article><p class=\"lead intro\"><a href=\"/a\">a</a><a>b</a></p>\
+             <div><p class=\"lead\"><a href=\"/c\">c</a></p></div><p class=\"note\">note</p>\
+             </article><p class=\"lead\"><a href=\"/d\">d</a></p>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><div></div><article><p class=\"lead intro\"><a>b</a></p>\
+             <div><p class=\"lead\"><a href=\"/c\">c</a></p></div><p class=\"note\"></p>\
+             </article><p class=\"lead\"><a href=\"/d\">d</a></p></html>"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid CSS selector list")]
+    fn select_nodes_with_invalid_selectors() {
+        let arena = typed_arena::Arena::new();
+        let nodes = parse_html(&arena, "<p>x</p>", false);
+        nodes[0].matches("p, a[href=]");
+    }
+
+    fn remove_non_paragraphs<'arena>(node: Ref<'arena>, _: Arena<'arena>) {
+        if let NodeData::Document = node.data {
+            let nodes: Vec<Ref> = node.select("div > :not(p), section > *").collect();
+            for node in nodes {
+                node.detach();
+            }
+        }
+    }
+
+    #[test]
+    fn select_nodes_only_matches_elements() {
+        let mut select_config = EMPTY_CONFIG.clone();
+        select_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("b"),
+            local_name!("div"),
+            local_name!("p"),
+            local_name!("section"),
+            local_name!("span"),
+        ]);
+        select_config.allow_comments = true;
+        let sanitizer = Sanitizer::new(&select_config, vec![&remove_non_paragraphs]);
+        let mut mock_data = MockRead::new(
+            "<div>one<!-- two --><p>three</p><span>four</span></div>\
+             <section>five<!-- six --><b>seven</b></section>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><div>one<!-- two --><p>three</p></div>\
+             <section>five<!-- six --></section></html>"
+        );
+    }
+
+    fn add_lazy_loading<'arena>(node: Ref<'arena>, _: Arena<'arena>) {
+        node.set_attr("loading", "lazy");
+    }
+
+    fn add_rule_before<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
+        node.insert_before(create_element(arena, "hr"));
+    }
+
+    #[test]
+    fn scoped_transformers() {
+        let mut transform_config = EMPTY_CONFIG.clone();
+        transform_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("iframe"),
+            local_name!("img"),
+        ]);
+        transform_config
+            .allowed_attributes
+            .extend(vec![local_name!("src")]);
+        let mut sanitizer = Sanitizer::new(&transform_config, vec![]);
+        sanitizer
+            .on("img[src], div > iframe", &add_lazy_loading)
+            .on_element("iframe", &add_rule_before);
+        let mut mock_data = MockRead::new(
+            "<img src=\"a.png\"><img><iframe src=\"/\"></iframe>\
+             <div><iframe src=\"/\"></iframe></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><img src=\"a.png\" loading=\"lazy\"><img><hr><iframe src=\"/\"></iframe>\
+             <div><hr><iframe src=\"/\" loading=\"lazy\"></iframe></div></html>"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid CSS selector list")]
+    fn scoped_transformers_with_invalid_selectors() {
+        let mut sanitizer = Sanitizer::new(&EMPTY_CONFIG, vec![]);
+        sanitizer.on("img[src], div >", &add_lazy_loading);
+    }
+
+    fn rearrange_nodes<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
+        if let NodeData::Document = node.data {
+            let p = node.select("p").next().unwrap();
+            let b = p.select("b").next().unwrap();
+            p.set_attr("title", &p.text_content());
+            p.set_attr("class", &p.inner_html());
+            b.replace_with(create_text(arena, "there"));
+            p.prepend(b);
+            b.set_text(arena, "hi");
+
+            let list = node.select("ul").next().unwrap();
+            let item = list.children().next().unwrap();
+            list.append(item.clone_subtree(arena));
+            item.set_attr("title", &item.outer_html());
+            list.wrap_in(create_element(arena, "div"));
+
+            let span = node.select("span").next().unwrap();
+            span.remove_attr("class");
+            span.set_attr("title", &span.get_attr("id").unwrap());
+            span.set_attr("id", &list.ancestors().count().to_string());
+        }
+    }
+
+    #[test]
+    fn manipulate_nodes() {
+        let mut manipulate_config = EMPTY_CONFIG.clone();
+        manipulate_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("b"),
+            local_name!("div"),
+            local_name!("li"),
+            local_name!("p"),
+            local_name!("span"),
+            local_name!("ul"),
+        ]);
+        manipulate_config.allowed_attributes.extend(vec![
+            local_name!("class"),
+            local_name!("id"),
+            local_name!("title"),
+        ]);
+        let sanitizer = Sanitizer::new(&manipulate_config, vec![&rearrange_nodes]);
+        let mut mock_data = MockRead::new(
+            "<p>hello <b>world</b></p><ul><li>one</li></ul>\
+             <span id=\"s\" class=\"c\">x</span><span>y</span>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><p title=\"hello world\" class=\"hello <b>world</b>\"><b>hi</b>hello there</p>\
+             <div><ul><li title=\"<li>one</li>\">one</li><li>one</li></ul></div>\
+             <span id=\"3\" title=\"s\">x</span><span>y</span></html>"
+        );
+    }
+
+    fn add_banners<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
+        let trusted = parse_html(
+            arena,
+            "<p onclick=\"show()\">Trusted <i>banner</i></p>",
+            true,
+        );
+        let untrusted = parse_html(
+            arena,
+            "<p onclick=\"show()\">Untrusted <i>banner</i></p>",
+            false,
+        );
+        let mut previous = node;
+        for banner in trusted.into_iter().chain(untrusted) {
+            previous.insert_after(banner);
+            previous = banner;
+        }
+    }
+
+    #[test]
+    fn insert_parsed_html() {
+        let mut banner_config = EMPTY_CONFIG.clone();
+        banner_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("iframe"),
+            local_name!("p"),
+        ]);
+        let mut sanitizer = Sanitizer::new(&banner_config, vec![]);
+        sanitizer.on_element("iframe", &add_banners);
+        let mut mock_data = MockRead::new("<iframe></iframe>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><iframe></iframe><p onclick=\"show()\">Trusted <i>banner</i></p>\
+             <p>Untrusted banner</p></html>"
+        );
+    }
+
+    fn remove_empty_links<'arena>(node: Ref<'arena>, _: Arena<'arena>) {
+        if node.matches("a, p") && node.text_content().trim().is_empty() {
+            node.detach();
+        }
+    }
+
+    #[test]
+    fn leave_transformers() {
+        let mut leave_config = EMPTY_CONFIG.clone();
+        leave_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("a"),
+            local_name!("p"),
+        ]);
+        leave_config
+            .remove_contents_when_unwrapped
+            .insert(local_name!("script"));
+        let mut sanitizer = Sanitizer::new(&leave_config, vec![]);
+        sanitizer.on_leave(&remove_empty_links);
+        let mut mock_data = MockRead::new(
+            "<p><a href=\"/\"><script>x</script></a></p><p>text <a><span> </span></a></p>\
+             <a>kept</a>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><p>text </p><a>kept</a></html>"
+        );
+    }
+
+    fn convert_center<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
+        if node.matches("center") {
+            let div = create_element(arena, "div");
+            div.set_attr("style", "text-align: center; color: red");
+            while let Some(child) = node.first_child.get() {
+                div.append(child);
+            }
+            node.replace_with(div);
+        }
+    }
+
+    #[test]
+    fn before_sanitize_transformers() {
+        let mut center_config = EMPTY_CONFIG.clone();
+        center_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("p"),
+        ]);
+        center_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        center_config
+            .allowed_css_properties
+            .extend(vec![css_property!("text-align")]);
+        let mut sanitizer = Sanitizer::new(&center_config, vec![]);
+        sanitizer.before_sanitize(&convert_center);
+        let mut mock_data =
+            MockRead::new("<center>one <b>two</b></center><center></center><p>three</p>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><div style=\"text-align: center;\">one two</div>\
+             <div style=\"text-align: center;\"></div><p>three</p></html>"
+        );
+    }
+
+    #[test]
+    fn rename_elements() {
+        let mut rename_config = EMPTY_CONFIG.clone();
+        rename_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("em"),
+            local_name!("h2"),
+            local_name!("span"),
+            local_name!("strong"),
+        ]);
+        rename_config
+            .allowed_attributes
+            .extend(vec![local_name!("style")]);
+        rename_config
+            .allowed_css_properties
+            .extend(vec![css_property!("color"), css_property!("text-align")]);
+        rename_config.rename_elements.extend(vec![
+            (local_name!("b"), ElementRename::to(local_name!("strong"))),
+            (local_name!("i"), ElementRename::to(local_name!("em"))),
+            (local_name!("h1"), ElementRename::to(local_name!("h2"))),
+            (
+                local_name!("center"),
+                ElementRename {
+                    css: Some("text-align: center"),
+                    ..ElementRename::to(local_name!("div"))
+                },
+            ),
+            (
+                local_name!("font"),
+                ElementRename {
+                    attributes_to_css: hashmap! { local_name!("color") => css_property!("color") },
+                    ..ElementRename::to(local_name!("span"))
+                },
+            ),
+        ]);
+        let sanitizer = Sanitizer::new(&rename_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<h1>Title</h1><center><b>one</b> <i>two</i></center>\
+             <font color=\"red\" face=\"serif\">three</font>\
+             <font color=\"red\" style=\"color: blue\">four</font><svg><font></font></svg>\
+             <font color=\"red; text-align: right\">five</font>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><h2>Title</h2><div style=\"text-align: center;\"><strong>one</strong> \
+             <em>two</em></div><span style=\"color: red;\">three</span>\
+             <span style=\"color: red; color: blue;\">four</span><span>five</span></html>"
+        );
+    }
+
+    #[test]
+    fn escape_disallowed_elements() {
+        let mut escape_config = EMPTY_CONFIG.clone();
+        escape_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("i"),
+            local_name!("p"),
+        ]);
+        escape_config.disallowed_element_disposition = ElementDisposition::Escape;
+        escape_config
+            .disallowed_element_disposition_per_element
+            .extend(vec![
+                (local_name!("b"), ElementDisposition::Unwrap),
+                (local_name!("iframe"), ElementDisposition::Remove),
+            ]);
+        escape_config
+            .remove_contents_when_unwrapped
+            .extend(vec![local_name!("script"), local_name!("style")]);
+        let sanitizer = Sanitizer::new(&escape_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<p>Use <script src=\"a.js\">alert(\"x\")</script> and \
+             <img src=\"x.png\" alt='a \"b\"'><b>bold <i>it</i></b></p>\
+             <iframe>hidden</iframe><style>p {}</style><span><i>text</i></span>\
+             <svg xlink:href=\"#a\"></svg>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><p>Use &lt;script src=\"a.js\"&gt;alert(\"x\")&lt;/script&gt; and \
+             &lt;img src=\"x.png\" alt='a \"b\"'&gt;bold <i>it</i></p>\
+             &lt;style&gt;p {}&lt;/style&gt;&lt;span&gt;<i>text</i>&lt;/span&gt;\
+             &lt;svg xlink:href=\"#a\"&gt;&lt;/svg&gt;</html>"
+        );
+    }
+
+    #[test]
+    fn remove_empty_elements() {
+        let mut empty_config = EMPTY_CONFIG.clone();
+        empty_config.allow_comments = true;
+        empty_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("a"),
+            local_name!("br"),
+            local_name!("li"),
+            local_name!("p"),
+            local_name!("span"),
+            local_name!("ul"),
+        ]);
+        empty_config.remove_empty_elements.extend(vec![
+            local_name!("a"),
+            local_name!("br"),
+            local_name!("li"),
+            local_name!("p"),
+            local_name!("span"),
+        ]);
+        let html = "<p> </p><p><span><b></b></span></p><ul><li></li><li>x</li></ul>\
+                    <a href=\"/\"><!-- c --></a><p><br></p><span>y</span>";
+
+        let sanitizer = Sanitizer::new(&empty_config, vec![]);
+        let mut mock_data = MockRead::new(html);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><p> </p><ul><li>x</li></ul><p><br></p><span>y</span></html>"
+        );
+
+        empty_config.remove_whitespace_only_elements = true;
+        let sanitizer = Sanitizer::new(&empty_config, vec![]);
+        let mut mock_data = MockRead::new(html);
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><ul><li>x</li></ul><p><br></p><span>y</span></html>"
+        );
+    }
+
+    fn wrap_bold<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
+        if node.matches("b") {
+            node.wrap_in(create_element(arena, "div"));
+        }
+    }
+
+    #[test]
+    fn before_sanitize_transformers_moving_nodes() {
+        let mut wrap_config = EMPTY_CONFIG.clone();
+        wrap_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("div"),
+            local_name!("img"),
+        ]);
+        wrap_config
+            .allowed_attributes
+            .extend(vec![local_name!("src")]);
+        wrap_config
+            .remove_contents_when_unwrapped
+            .extend(vec![local_name!("script")]);
+        let mut sanitizer = Sanitizer::new(&wrap_config, vec![]);
+        sanitizer.before_sanitize(&wrap_bold);
+        let mut mock_data =
+            MockRead::new("<b>x</b><img src=x onerror=alert(1)><script>alert(2)</script><b>y</b>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><div>x</div><img src=\"x\"><div>y</div></html>"
+        );
+    }
+
+    #[test]
+    fn remove_doctype() {
+        let mut disallow_doctype_config = EMPTY_CONFIG.clone();
+        disallow_doctype_config.allow_doctype = false;
+        disallow_doctype_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("div")]);
+        let sanitizer = Sanitizer::new(&disallow_doctype_config, vec![]);
+        let mut mock_data = MockRead::new("<!DOCTYPE html><div></div>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_document(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(str::from_utf8(&output).unwrap(), "<html><div></div></html>");
+    }
+
+    #[test]
+    fn allow_doctype() {
+        let mut allow_doctype_config = EMPTY_CONFIG.clone();
+        allow_doctype_config.allow_doctype = true;
+        allow_doctype_config
             .allowed_elements
             .extend(vec![local_name!("html"), local_name!("div")]);
         let sanitizer = Sanitizer::new(&allow_doctype_config, vec![]);
@@ -903,4 +3608,451 @@ mod test {
             "<html><div>div-1 content-1  content-2 div-2</div></html>"
         );
     }
+
+    #[test]
+    fn allow_svg_elements() {
+        let mut svg_config = EMPTY_CONFIG.clone();
+        svg_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("div")]);
+        svg_config
+            .allowed_svg_elements
+            .extend(vec![local_name!("svg"), local_name!("path")]);
+        svg_config
+            .allowed_svg_attributes
+            .extend(vec![local_name!("viewBox"), local_name!("d")]);
+        let sanitizer = Sanitizer::new(&svg_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<div><svg viewBox=\"0 0 10 10\" onload=\"alert(1)\">\
+                <path d=\"M0 0\" onclick=\"alert(1)\"></path>\
+                <script>alert(1)</script>\
+            </svg></div>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><div><svg viewBox=\"0 0 10 10\"><path d=\"M0 0\"></path>alert(1)</svg>\
+             </div></html>"
+        );
+    }
+
+    #[test]
+    fn svg_and_html_allowlists_are_separate() {
+        let mut svg_config = EMPTY_CONFIG.clone();
+        svg_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("a")]);
+        svg_config
+            .allowed_attributes
+            .extend(vec![local_name!("href")]);
+        svg_config
+            .allowed_svg_elements
+            .extend(vec![local_name!("svg")]);
+        let sanitizer = Sanitizer::new(&svg_config, vec![]);
+        let mut mock_data =
+            MockRead::new("<a href=\"/html\">html</a><svg><a href=\"/svg\">svg</a></svg>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><a href=\"/html\">html</a><svg>svg</svg></html>"
+        );
+    }
+
+    #[test]
+    fn sanitize_svg_attribute_protocols() {
+        let mut svg_config = EMPTY_CONFIG.clone();
+        svg_config
+            .allowed_elements
+            .extend(vec![local_name!("html")]);
+        svg_config
+            .allowed_svg_elements
+            .extend(vec![local_name!("svg"), local_name!("a")]);
+        svg_config
+            .allowed_svg_attributes
+            .extend(vec![local_name!("href")]);
+        svg_config.allowed_svg_protocols.insert(
+            local_name!("href"),
+            hashset! { Protocol::Scheme("https"), Protocol::Relative },
+        );
+        let sanitizer = Sanitizer::new(&svg_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<svg>\
+                <a href=\"javascript:alert(1)\">1</a>\
+                <a xlink:href=\"javascript:alert(1)\">2</a>\
+                <a xlink:href=\"#fragment\">3</a>\
+                <a href=\"https://example.com\">4</a>\
+            </svg>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><svg>\
+                <a>1</a>\
+                <a>2</a>\
+                <a xlink:href=\"#fragment\">3</a>\
+                <a href=\"https://example.com\">4</a>\
+            </svg></html>"
+        );
+    }
+
+    #[test]
+    fn remove_svg_animations_of_href() {
+        let mut svg_config = EMPTY_CONFIG.clone();
+        svg_config
+            .allowed_elements
+            .extend(vec![local_name!("html")]);
+        svg_config.allowed_svg_elements.extend(vec![
+            local_name!("svg"),
+            local_name!("a"),
+            local_name!("animate"),
+            local_name!("set"),
+        ]);
+        svg_config
+            .allowed_svg_attributes
+            .extend(vec![local_name!("attributeName"), local_name!("to")]);
+        let sanitizer = Sanitizer::new(&svg_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<svg><a>\
+                <animate attributeName=\"xlink:href\" to=\"javascript:alert(1)\"></animate>\
+                <set attributeName=\" HREF\" to=\"javascript:alert(1)\"></set>\
+                <animate attributeName=\"opacity\" to=\"0\"></animate>\
+            </a></svg>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><svg><a>\
+                <animate attributeName=\"opacity\" to=\"0\"></animate>\
+            </a></svg></html>"
+        );
+    }
+
+    #[test]
+    fn svg_config_blocks_dangerous_svg() {
+        let sanitizer = Sanitizer::new(&SVG_CONFIG, vec![]);
+        let mut mock_data = MockRead::new(
+            "<svg viewBox=\"0 0 10 10\">\
+                <g fill=\"red\"><circle cx=\"5\" cy=\"5\" r=\"4\"></circle></g>\
+                <script>alert(1)</script>\
+                <foreignObject><iframe src=\"https://example.com\"></iframe></foreignObject>\
+                <use href=\"https://example.com/sprite.svg#icon\"></use>\
+                <set attributeName=\"href\" to=\"javascript:alert(1)\"></set>\
+            </svg>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><svg viewBox=\"0 0 10 10\">\
+                <g fill=\"red\"><circle cx=\"5\" cy=\"5\" r=\"4\"></circle></g>\
+            </svg></html>"
+        );
+    }
+
+    #[test]
+    fn allow_mathml_elements() {
+        let mut mathml_config = EMPTY_CONFIG.clone();
+        mathml_config
+            .allowed_elements
+            .extend(vec![local_name!("html")]);
+        mathml_config.allowed_mathml_elements.extend(vec![
+            local_name!("math"),
+            local_name!("mi"),
+            local_name!("mo"),
+        ]);
+        mathml_config
+            .allowed_mathml_attributes
+            .extend(vec![local_name!("mathvariant")]);
+        let sanitizer = Sanitizer::new(&mathml_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<math href=\"javascript:alert(1)\">\
+                <mi mathvariant=\"bold\">x</mi><mo>+</mo><maction>y</maction>\
+            </math>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><math><mi mathvariant=\"bold\">x</mi><mo>+</mo>y</math></html>"
+        );
+    }
+
+    #[test]
+    fn remove_elements_in_wrong_namespace() {
+        let sanitizer = Sanitizer::new(&MATHML_CONFIG, vec![]);
+        let mut mock_data = MockRead::new("<div><svg><circle r=\"1\"></circle>text</svg></div>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><div>text</div></html>"
+        );
+    }
+
+    #[test]
+    fn mathml_mutation_xss() {
+        let sanitizer = Sanitizer::new(&MATHML_CONFIG, vec![]);
+        let payloads = vec![
+            (
+                "<math><mtext><table><mglyph><style><img src=x onerror=alert(1)>",
+                "<html><math><mtext><style></style><table></table></mtext></math></html>",
+            ),
+            (
+                "<math><mtext><table><mglyph><style><!--</style>\
+                    <img title=\"--&gt;&lt;/mglyph&gt;\
+                    &lt;img&Tab;src=1&Tab;onerror=alert(1)&gt;\">",
+                "<html><math><mtext><style></style>\
+                    <img title=\"--></mglyph><img\tsrc=1\tonerror=alert(1)>\">\
+                    <table></table></mtext></math></html>",
+            ),
+            (
+                "<form><math><mtext></form><form><mglyph><style></math>\
+                    <img src onerror=alert(1)>",
+                "<html><math><mtext><style></style></mtext></math></html>",
+            ),
+            (
+                "<math><annotation-xml encoding=\"text/html\">\
+                    <style><img src=x onerror=alert(1)></style>\
+                </annotation-xml></math>",
+                "<html><math></math></html>",
+            ),
+            (
+                "<math><mi><svg><style><img src=x onerror=alert(1)></style></svg></mi></math>",
+                "<html><math><mi></mi></math></html>",
+            ),
+        ];
+        for (payload, expected) in payloads {
+            let mut mock_data = MockRead::new(payload);
+            let mut output = vec![];
+            sanitizer
+                .sanitize_fragment(&mut mock_data, &mut output)
+                .unwrap();
+            assert_eq!(str::from_utf8(&output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn add_attributes_overwrites_existing() {
+        let mut add_attributes_config = EMPTY_CONFIG.clone();
+        add_attributes_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("a")]);
+        add_attributes_config
+            .allowed_attributes
+            .extend(vec![local_name!("rel")]);
+        add_attributes_config.add_attributes_per_element.insert(
+            local_name!("a"),
+            hashmap! { local_name!("rel") => "nofollow" },
+        );
+        let sanitizer = Sanitizer::new(&add_attributes_config, vec![]);
+        let mut mock_data = MockRead::new("<a rel=\"me\"></a>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><a rel=\"nofollow\"></a></html>"
+        );
+    }
+
+    #[test]
+    fn escape_style_end_tag_in_css() {
+        let mut sanitize_css_config = EMPTY_CONFIG.clone();
+        sanitize_css_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("style")]);
+        sanitize_css_config
+            .allowed_css_properties
+            .extend(vec![css_property!("content")]);
+        let sanitizer = Sanitizer::new(&sanitize_css_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<style>div { content: \"\\3c/style\\3e\\3cimg src=x onerror=alert(1)\\3e\" }</style>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><style>div { content: \"<\\/style><img src=x onerror=alert(1)>\" ; }</style>\
+             </html>"
+        );
+    }
+
+    #[test]
+    fn round_trip_check_reaches_fixpoint() {
+        let mut round_trip_config = EMPTY_CONFIG.clone();
+        round_trip_config.round_trip_check = true;
+        round_trip_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("p"),
+            local_name!("div"),
+        ]);
+        let sanitizer = Sanitizer::new(&round_trip_config, vec![]);
+        // Unwrapping the <button> leaves a <div> inside a <p>, which the parser won't recreate.
+        let mut mock_data = MockRead::new("<p><button><div>x</div></button></p>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><p></p><div>x</div><p></p></html>"
+        );
+    }
+
+    fn mark_external<'arena>(node: Ref<'arena>, arena: Arena<'arena>) {
+        node.append(create_text(arena, " (external)"));
+    }
+
+    #[test]
+    fn round_trip_check_skips_transformers() {
+        let mut round_trip_config = EMPTY_CONFIG.clone();
+        round_trip_config.round_trip_check = true;
+        round_trip_config
+            .allowed_elements
+            .extend(vec![local_name!("html"), local_name!("a")]);
+        round_trip_config
+            .allowed_attributes
+            .extend(vec![local_name!("href")]);
+        let mut sanitizer = Sanitizer::new(&round_trip_config, vec![]);
+        sanitizer.on_element("a", &mark_external);
+        let mut mock_data = MockRead::new("<a href=\"https://x\">x</a>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><a href=\"https://x\">x (external)</a></html>"
+        );
+    }
+
+    #[test]
+    fn unwrap_disallowed_foreign_elements() {
+        let sanitizer = Sanitizer::new(&BASIC_CONFIG, vec![]);
+        let mut mock_data =
+            MockRead::new("<p><math><mi>keep me</mi></math> and <svg><text>me</text></svg></p>");
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(str::from_utf8(&output).unwrap(), "<p>keep me and me</p>");
+    }
+
+    #[test]
+    fn mutation_xss_regressions() {
+        let mut mxss_config = MATHML_CONFIG.clone();
+        mxss_config.round_trip_check = true;
+        mxss_config
+            .allowed_svg_elements
+            .extend(SVG_CONFIG.allowed_svg_elements.clone());
+        mxss_config
+            .allowed_svg_attributes
+            .extend(SVG_CONFIG.allowed_svg_attributes.clone());
+        mxss_config
+            .allowed_svg_protocols
+            .extend(SVG_CONFIG.allowed_svg_protocols.clone());
+        mxss_config
+            .allowed_elements
+            .extend(vec![local_name!("form"), local_name!("noscript")]);
+        let sanitizer = Sanitizer::new(&mxss_config, vec![]);
+        // Payloads collected from DOMPurify's bypass history.
+        let payloads = vec![
+            (
+                "<svg></p><style><a id=\"</style><img src=1 onerror=alert(1)>\">",
+                "<html><svg></svg></html>",
+            ),
+            (
+                "<svg><p><style><a id=\"</style><img src=1 onerror=alert(1)>\">",
+                "<html><svg></svg></html>",
+            ),
+            (
+                "<math><mtext><table><mglyph><style><!--</style>\
+                    <img title=\"--&gt;&lt;/mglyph&gt;\
+                    &lt;img&Tab;src=1&Tab;onerror=alert(1)&gt;\">",
+                "<html><math><mtext><style></style>\
+                    <img title=\"--></mglyph><img\tsrc=1\tonerror=alert(1)>\">\
+                    <table></table></mtext></math></html>",
+            ),
+            (
+                "<form><math><mtext></form><form><mglyph><style></math>\
+                    <img src onerror=alert(1)>",
+                "<html><form><math><mtext><style></style></mtext></math></form></html>",
+            ),
+            (
+                "<math><mtext><h1><a><h6></a></h6><mglyph><svg><mtext><style>\
+                    <a title=\"</style><img src onerror=alert(1)>\"></style></h1>",
+                "<html><math><mtext><h1><a rel=\"href\"></a></h1><h6><a rel=\"href\"></a></h6>\
+                    <svg></svg></mtext></math></html>",
+            ),
+            (
+                "<svg><a><foreignObject><a><table><a></table><style><!--</style></svg>\
+                    <a id=\"-><img src onerror=alert(1)>\">",
+                "<html><svg><a></a></svg>\
+                    <a id=\"-><img src onerror=alert(1)>\" rel=\"href\"></a></html>",
+            ),
+            (
+                "<noscript><p title=\"</noscript><img src=x onerror=alert(1)>\">",
+                "<html><noscript><p title=\"</noscript><img src=\"x\">\"&gt;</html>",
+            ),
+            (
+                "<svg><style><img src=x onerror=alert(1)></style></svg>",
+                "<html><svg></svg></html>",
+            ),
+        ];
+        for (payload, expected) in payloads {
+            let mut mock_data = MockRead::new(payload);
+            let mut output = vec![];
+            sanitizer
+                .sanitize_fragment(&mut mock_data, &mut output)
+                .unwrap();
+            assert_eq!(str::from_utf8(&output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn sanitize_template_contents() {
+        let mut template_config = EMPTY_CONFIG.clone();
+        template_config.allowed_elements.extend(vec![
+            local_name!("html"),
+            local_name!("template"),
+            local_name!("p"),
+        ]);
+        template_config
+            .remove_contents_when_unwrapped
+            .insert(local_name!("script"));
+        let sanitizer = Sanitizer::new(&template_config, vec![]);
+        let mut mock_data = MockRead::new(
+            "<template><script>alert(1)</script><p onclick=\"alert(1)\">ok</p>\
+                <template><b>nested</b></template></template>",
+        );
+        let mut output = vec![];
+        sanitizer
+            .sanitize_fragment(&mut mock_data, &mut output)
+            .unwrap();
+        assert_eq!(
+            str::from_utf8(&output).unwrap(),
+            "<html><template><p>ok</p><template>nested</template></template></html>"
+        );
+    }
 }